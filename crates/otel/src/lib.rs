@@ -4,7 +4,11 @@
 //! reporting OpenTelemetry-based metrics.
 
 pub mod init;
+#[cfg(feature = "state")]
+pub mod state;
 pub mod tracing;
 
 pub use init::Telemetry;
+#[cfg(feature = "state")]
+pub use state::TracedStore;
 pub use tracing::*;
@@ -0,0 +1,298 @@
+//! # State
+//!
+//! Instrumentation for [`StateStore`] implementations.
+//!
+//! [`TracedStore`] wraps a store, emitting a span for each operation along
+//! with OpenTelemetry metrics for operation latency, read outcomes and
+//! payload size. Spans are created under the current span, so storage
+//! operations appear beneath the request spans emitted by
+//! [`TracingService`](crate::TracingService).
+//!
+//! Keys are not recorded as they commonly hold single-use secrets such as
+//! authorization codes and nonces.
+
+use std::future::Future;
+use std::io;
+use std::time::Instant;
+
+use credibil_core::state::{KeyInfo, Page, State, StateError, StateStore};
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::{KeyValue, global};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tracing::Instrument;
+
+/// A [`StateStore`] that emits tracing spans and OpenTelemetry metrics for
+/// each operation on the wrapped store.
+///
+/// The following metrics are recorded:
+///
+/// - `state.operation.duration`: operation latency, in seconds, by operation
+///   and outcome.
+/// - `state.reads`: the number of keys read, by result (`hit`, `miss`, or
+///   `expired`).
+/// - `state.payload.size`: the JSON-serialized size, in bytes, of state
+///   written.
+///
+/// Batch reads do not distinguish missing from expired state, so keys missed
+/// by [`StateStore::get_many`] are counted as `miss`. Read payloads are not
+/// measured as read bodies are decoded directly to the requested type.
+#[derive(Clone, Debug)]
+pub struct TracedStore<S> {
+    inner: S,
+    duration: Histogram<f64>,
+    reads: Counter<u64>,
+    payload: Histogram<u64>,
+}
+
+impl<S: StateStore> TracedStore<S> {
+    /// Wrap `inner` with instrumentation, recording metrics using the global
+    /// meter provider.
+    pub fn new(inner: S) -> Self {
+        Self::with_meter(inner, &global::meter("state_store"))
+    }
+
+    /// Wrap `inner` with instrumentation, recording metrics using `meter`.
+    pub fn with_meter(inner: S, meter: &Meter) -> Self {
+        Self {
+            inner,
+            duration: meter.f64_histogram("state.operation.duration").with_unit("s").build(),
+            reads: meter.u64_counter("state.reads").build(),
+            payload: meter.u64_histogram("state.payload.size").with_unit("By").build(),
+        }
+    }
+
+    /// Consume the wrapper, returning the inner store.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    // Run `operation` in a span, recording its latency and outcome.
+    async fn traced<R>(
+        &self, operation: &'static str, owner: &str,
+        future: impl Future<Output = Result<R, StateError>>,
+    ) -> Result<R, StateError> {
+        let span = tracing::info_span!(
+            target: "state",
+            "state",
+            otel.name = format!("state.{operation}"),
+            otel.kind = "client",
+            otel.status_code = tracing::field::Empty,
+            state.operation = operation,
+            state.owner = owner,
+            state.outcome = tracing::field::Empty,
+        );
+
+        let start = Instant::now();
+        let result = future.instrument(span.clone()).await;
+        let outcome = outcome(&result);
+
+        span.record("state.outcome", outcome);
+        if let Err(e) = &result {
+            span.record("otel.status_code", "ERROR");
+            tracing::debug!(parent: &span, error = %e, "state operation failed");
+        }
+        self.duration.record(
+            start.elapsed().as_secs_f64(),
+            &[KeyValue::new("state.operation", operation), KeyValue::new("state.outcome", outcome)],
+        );
+
+        result
+    }
+
+    // Count a single read by its result.
+    fn record_read<T>(&self, result: &Result<State<T>, StateError>) {
+        let read = match result {
+            Ok(_) => "hit",
+            Err(StateError::NotFound(_)) => "miss",
+            Err(StateError::Expired(_)) => "expired",
+            Err(_) => return,
+        };
+        self.reads.add(1, &[KeyValue::new("state.result", read)]);
+    }
+
+    fn record_size<T: Serialize>(&self, operation: &'static str, state: &State<T>) {
+        let mut counter = ByteCounter::default();
+        if serde_json::to_writer(&mut counter, state).is_ok() {
+            self.payload.record(counter.0, &[KeyValue::new("state.operation", operation)]);
+        }
+    }
+}
+
+impl<S: StateStore> StateStore for TracedStore<S> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        self.record_size("put", state);
+        self.traced("put", owner, self.inner.put(owner, key, state)).await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let result = self.traced("get", owner, self.inner.get(owner, key)).await;
+        self.record_read(&result);
+        result
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        self.traced("purge", owner, self.inner.purge(owner, key)).await
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let result = self.traced("take", owner, self.inner.take(owner, key)).await;
+        self.record_read(&result);
+        result
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        self.record_size("put_if_version", state);
+        self.traced("put_if_version", owner, self.inner.put_if_version(owner, key, state)).await
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        self.traced("list", owner, self.inner.list(owner, prefix, cursor, limit)).await
+    }
+
+    async fn scan(&self, owner: &str, prefix: &str) -> Result<Vec<KeyInfo>, StateError> {
+        self.traced("scan", owner, self.inner.scan(owner, prefix)).await
+    }
+
+    async fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        let states = self.traced("get_many", owner, self.inner.get_many(owner, keys)).await?;
+        let hits = states.iter().filter(|state| state.is_some()).count() as u64;
+        self.reads.add(hits, &[KeyValue::new("state.result", "hit")]);
+        self.reads.add(keys.len() as u64 - hits, &[KeyValue::new("state.result", "miss")]);
+        Ok(states)
+    }
+
+    async fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        for (_, state) in entries {
+            self.record_size("put_many", state);
+        }
+        self.traced("put_many", owner, self.inner.put_many(owner, entries)).await
+    }
+
+    async fn purge_many(&self, owner: &str, keys: &[&str]) -> Result<(), StateError> {
+        self.traced("purge_many", owner, self.inner.purge_many(owner, keys)).await
+    }
+}
+
+// The outcome of an operation, recorded as a span field and metric attribute.
+const fn outcome<R>(result: &Result<R, StateError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(e) => e.code(),
+    }
+}
+
+// Counts bytes written without storing them.
+#[derive(Default)]
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::TimeDelta;
+    use credibil_core::state::{Cbor, InMemoryStore};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::data::{
+        AggregatedMetrics, MetricData, ResourceMetrics, ScopeMetrics, SumDataPoint,
+    };
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, SdkMeterProvider};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn pass_through() {
+        let store = TracedStore::new(InMemoryStore::new());
+        let state = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        store.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get"), state);
+
+        store.purge("owner", "key").await.expect("should purge");
+        let err = store.get::<String>("owner", "key").await.expect_err("should be purged");
+        assert!(matches!(err, StateError::NotFound(_)));
+
+        // bodies are decoded by the inner store's codec, not via JSON
+        let store = TracedStore::new(InMemoryStore::new().with_codec(Cbor));
+        let state = State::with_ttl(BTreeMap::from([(1_u8, "alice".to_string())]), TimeDelta::MAX);
+        store.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(
+            store.get::<BTreeMap<u8, String>>("owner", "key").await.expect("should get"),
+            state
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder().with_periodic_exporter(exporter.clone()).build();
+        let store = TracedStore::with_meter(InMemoryStore::new(), &provider.meter("test"));
+
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+        let expired = State::with_ttl("bob".to_string(), TimeDelta::seconds(-1));
+        store.put("owner", "alice", &alice).await.expect("should put");
+        store.put("owner", "expired", &expired).await.expect("should put");
+
+        store.get::<String>("owner", "alice").await.expect("should get");
+        store.get::<String>("owner", "expired").await.expect_err("should be expired");
+        store.take::<String>("owner", "missing").await.expect_err("should be missing");
+        store.get_many::<String>("owner", &["alice", "missing"]).await.expect("should get many");
+        provider.force_flush().expect("should flush");
+
+        let metrics = exporter.get_finished_metrics().expect("should export");
+        let metrics = metrics
+            .iter()
+            .flat_map(ResourceMetrics::scope_metrics)
+            .flat_map(ScopeMetrics::metrics)
+            .collect::<Vec<_>>();
+        let names = metrics.iter().map(|metric| metric.name()).collect::<Vec<_>>();
+        assert!(names.contains(&"state.operation.duration"));
+        assert!(names.contains(&"state.payload.size"));
+
+        let reads = metrics
+            .iter()
+            .find(|metric| metric.name() == "state.reads")
+            .expect("should record reads");
+        let AggregatedMetrics::U64(MetricData::Sum(sum)) = reads.data() else {
+            panic!("reads should be a u64 sum");
+        };
+        let count = |result: &str| {
+            sum.data_points()
+                .filter(|point| {
+                    point
+                        .attributes()
+                        .any(|kv| kv == &KeyValue::new("state.result", result.to_string()))
+                })
+                .map(SumDataPoint::value)
+                .sum::<u64>()
+        };
+        assert_eq!(count("hit"), 2);
+        assert_eq!(count("miss"), 2);
+        assert_eq!(count("expired"), 1);
+    }
+}
@@ -1,11 +1,34 @@
 //! # Core
 
+pub mod clock;
+pub mod one_many;
+pub mod resolver;
 pub mod state;
 
+use std::fmt::{self, Display};
+use std::{mem, slice};
+
+use anyhow::Result;
+use serde::de::value::{
+    BoolDeserializer, BorrowedBytesDeserializer, BorrowedStrDeserializer, BytesDeserializer,
+    EnumAccessDeserializer, F64Deserializer, I64Deserializer, I128Deserializer,
+    MapAccessDeserializer, SeqAccessDeserializer, StrDeserializer, StringDeserializer,
+    U64Deserializer, U128Deserializer, UnitDeserializer,
+};
+use serde::de::{self, Deserializer, EnumAccess, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 
+pub use self::one_many::OneManySet;
+#[cfg(feature = "cache")]
+pub use self::resolver::CachingResolver;
+pub use self::resolver::Resolver;
+
 /// `Kind` allows serde to serialize/deserialize a string or an object.
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+///
+/// A string deserializes as `Kind::String` and any other value as
+/// `Kind::Object`. Errors deserializing the object report the path to the
+/// invalid field.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Kind<T> {
     /// Simple string value
@@ -33,8 +56,30 @@ impl<T> From<&str> for Kind<T> {
     }
 }
 
+/// Returns the string, or the `Kind` unchanged if it holds an object.
+impl<T> TryFrom<Kind<T>> for String {
+    type Error = Kind<T>;
+
+    fn try_from(value: Kind<T>) -> Result<Self, Self::Error> {
+        match value {
+            Kind::String(s) => Ok(s),
+            Kind::Object(_) => Err(value),
+        }
+    }
+}
+
+/// Displays the string or, for an object, the object's `Display` output.
+impl<T: Display> Display for Kind<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(s) => f.write_str(s),
+            Self::Object(o) => o.fmt(f),
+        }
+    }
+}
+
 impl<T> Kind<T> {
-    /// Returns `true` if the quota is a single object.
+    /// Returns the string, if the `Kind` holds a string.
     pub const fn as_str(&self) -> Option<&str> {
         match self {
             Self::String(s) => Some(s.as_str()),
@@ -42,18 +87,93 @@ impl<T> Kind<T> {
         }
     }
 
-    /// Returns `true` if the quota contains an array of objects.
+    /// Returns the object, if the `Kind` holds an object.
     pub const fn as_object(&self) -> Option<&T> {
         match self {
             Self::String(_) => None,
             Self::Object(o) => Some(o),
         }
     }
+
+    /// Returns `true` if the `Kind` holds a string.
+    pub const fn is_string(&self) -> bool {
+        matches!(self, Self::String(_))
+    }
+
+    /// Returns `true` if the `Kind` holds an object.
+    pub const fn is_object(&self) -> bool {
+        matches!(self, Self::Object(_))
+    }
+
+    /// Converts the `Kind` into its string, if it holds a string.
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Self::String(s) => Some(s),
+            Self::Object(_) => None,
+        }
+    }
+
+    /// Converts the `Kind` into its object, if it holds an object.
+    pub fn into_object(self) -> Option<T> {
+        match self {
+            Self::String(_) => None,
+            Self::Object(o) => Some(o),
+        }
+    }
+
+    /// Converts the `Kind` into its object, returning the `Kind` unchanged
+    /// if it holds a string.
+    ///
+    /// This is the object counterpart of `String::try_from(kind)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the `Kind` if it holds a string.
+    pub fn try_into_object(self) -> Result<T, Self> {
+        match self {
+            Self::String(_) => Err(self),
+            Self::Object(o) => Ok(o),
+        }
+    }
+
+    /// Converts a `&Kind<T>` to a `Kind<&T>`. A string is cloned.
+    pub fn as_ref(&self) -> Kind<&T> {
+        match self {
+            Self::String(s) => Kind::String(s.clone()),
+            Self::Object(o) => Kind::Object(o),
+        }
+    }
+
+    /// Converts the object using `f`, leaving a string unchanged.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Kind<U> {
+        match self {
+            Self::String(s) => Kind::String(s),
+            Self::Object(o) => Kind::Object(f(o)),
+        }
+    }
+
+    /// Returns the object, using `resolver` to resolve a string reference.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the reference cannot be resolved.
+    pub async fn resolve(self, resolver: &impl Resolver<T>) -> Result<T> {
+        match self {
+            Self::String(reference) => resolver.resolve(&reference).await,
+            Self::Object(object) => Ok(object),
+        }
+    }
 }
 
 /// `OneMany` allows serde to serialize/deserialize a single object or a set of
 /// objects.
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+///
+/// An array deserializes as `OneMany::Many` and any other value as
+/// `OneMany::One`, even when `T` could itself be deserialized from an array
+/// (e.g. `OneMany<Value>` or `OneMany<Vec<_>>`). Errors deserializing an
+/// object report the path to the invalid field, including the index of an
+/// invalid array item.
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum OneMany<T> {
     /// Single object
@@ -63,20 +183,167 @@ pub enum OneMany<T> {
     Many(Vec<T>),
 }
 
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Kind<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UntaggedVisitor {
+            expecting: "a string or an object",
+            object: Self::Object,
+            string: Some(Self::String),
+            many: None,
+        })
+    }
+}
+
 impl<T: Default> Default for OneMany<T> {
     fn default() -> Self {
         Self::One(T::default())
     }
 }
 
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for OneMany<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UntaggedVisitor {
+            expecting: "an object or an array of objects",
+            object: Self::One,
+            string: None,
+            many: Some(Self::Many),
+        })
+    }
+}
+
+// Deserializes `Kind` and `OneMany` from any self-describing format without
+// buffering the input. The variant is chosen from the shape of the input: a
+// string for `Kind::String`, a sequence for `OneMany::Many`, and anything
+// else for the single-object variant. Errors deserializing an object report
+// the path to the invalid field.
+struct UntaggedVisitor<T, V> {
+    expecting: &'static str,
+    object: fn(T) -> V,
+    string: Option<fn(String) -> V>,
+    many: Option<fn(Vec<T>) -> V>,
+}
+
+impl<'de, T: Deserialize<'de>, V> UntaggedVisitor<T, V> {
+    fn object<D: Deserializer<'de>>(&self, deserializer: D) -> Result<V, D::Error> {
+        serde_path_to_error::deserialize(deserializer)
+            .map(self.object)
+            .map_err(|e| invalid(self.expecting, &e))
+    }
+}
+
+impl<'de, T: Deserialize<'de>, V> Visitor<'de> for UntaggedVisitor<T, V> {
+    type Value = V;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(self.expecting)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<V, E> {
+        self.object(BoolDeserializer::new(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<V, E> {
+        self.object(I64Deserializer::new(v))
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<V, E> {
+        self.object(I128Deserializer::new(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<V, E> {
+        self.object(U64Deserializer::new(v))
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<V, E> {
+        self.object(U128Deserializer::new(v))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<V, E> {
+        self.object(F64Deserializer::new(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<V, E> {
+        if let Some(string) = self.string {
+            return Ok(string(v.to_string()));
+        }
+        self.object(StrDeserializer::new(v))
+    }
+
+    fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<V, E> {
+        if let Some(string) = self.string {
+            return Ok(string(v.to_string()));
+        }
+        self.object(BorrowedStrDeserializer::new(v))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<V, E> {
+        if let Some(string) = self.string {
+            return Ok(string(v));
+        }
+        self.object(StringDeserializer::new(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<V, E> {
+        self.object(BytesDeserializer::new(v))
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<V, E> {
+        self.object(BorrowedBytesDeserializer::new(v))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<V, E> {
+        self.object(UnitDeserializer::new())
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<V, D::Error> {
+        self.object(deserializer)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<V, E> {
+        self.object(UnitDeserializer::new())
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<V, D::Error> {
+        self.object(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<V, A::Error> {
+        let Some(many) = self.many else {
+            return self.object(SeqAccessDeserializer::new(seq));
+        };
+        serde_path_to_error::deserialize(SeqAccessDeserializer::new(seq))
+            .map(many)
+            .map_err(|e| invalid("an array of objects", &e))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<V, A::Error> {
+        self.object(MapAccessDeserializer::new(map))
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<V, A::Error> {
+        self.object(EnumAccessDeserializer::new(data))
+    }
+}
+
+// Describes a failure to deserialize a `Kind` or `OneMany` variant, along
+// with the path to the invalid field.
+fn invalid<E: de::Error>(expected: &str, error: &serde_path_to_error::Error<E>) -> E {
+    let path = error.path().to_string();
+    if path == "." {
+        E::custom(format!("expected {expected}: {}", error.inner()))
+    } else {
+        E::custom(format!("expected {expected}: invalid value at `{path}`: {}", error.inner()))
+    }
+}
+
 impl<T> From<T> for OneMany<T> {
     fn from(value: T) -> Self {
         Self::One(value)
     }
 }
 
-impl<T: Clone + Default + PartialEq> OneMany<T> {
-    /// Returns `true` if the quota is a single object.
+impl<T> OneMany<T> {
+    /// Returns `true` if the `OneMany` is a single object.
     pub const fn as_one(&self) -> Option<&T> {
         match self {
             Self::One(o) => Some(o),
@@ -92,20 +359,80 @@ impl<T: Clone + Default + PartialEq> OneMany<T> {
         }
     }
 
-    /// Adds an object to the quota. If the quota is a single object, it is
-    /// converted to a set of objects.
-    pub fn add(&mut self, item: T) {
+    /// Returns the contents as a slice, regardless of variant.
+    pub const fn as_slice(&self) -> &[T] {
+        match self {
+            Self::One(one) => slice::from_ref(one),
+            Self::Many(many) => many.as_slice(),
+        }
+    }
+
+    /// Returns the contents as a mutable slice, regardless of variant.
+    pub const fn as_mut_slice(&mut self) -> &mut [T] {
+        match self {
+            Self::One(one) => slice::from_mut(one),
+            Self::Many(many) => many.as_mut_slice(),
+        }
+    }
+
+    /// Returns an iterator over the contained objects.
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+
+    /// Returns an iterator allowing the contained objects to be modified.
+    pub fn iter_mut(&mut self) -> slice::IterMut<'_, T> {
+        self.as_mut_slice().iter_mut()
+    }
+
+    /// Returns the first object, or `None` if the `OneMany` is an empty `Many`.
+    pub const fn first(&self) -> Option<&T> {
+        self.as_slice().first()
+    }
+
+    /// Returns `true` if the `OneMany` contains an object equal to `item`.
+    pub fn contains(&self, item: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        self.as_slice().contains(item)
+    }
+
+    /// Converts each object using `f`, preserving the variant.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> OneMany<U> {
+        match self {
+            Self::One(one) => OneMany::One(f(one)),
+            Self::Many(many) => OneMany::Many(many.into_iter().map(f).collect()),
+        }
+    }
+
+    /// Retains only the objects for which `f` returns `true`. A single
+    /// object that is not retained leaves an empty `Many`.
+    pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
         match self {
             Self::One(one) => {
-                *self = Self::Many(vec![one.clone(), item]);
+                if !f(one) {
+                    *self = Self::Many(Vec::new());
+                }
             }
-            Self::Many(many) => {
+            Self::Many(many) => many.retain(f),
+        }
+    }
+
+    /// Adds an object to the `OneMany`. If the `OneMany` is a single object,
+    /// it is converted to a set of objects.
+    pub fn add(&mut self, item: T) {
+        let many = match mem::replace(self, Self::Many(Vec::new())) {
+            Self::One(one) => vec![one, item],
+            Self::Many(mut many) => {
                 many.push(item);
+                many
             }
-        }
+        };
+        *self = Self::Many(many);
     }
 
-    /// Returns the length of the quota.
+    /// Returns the length of the `OneMany`.
     pub const fn len(&self) -> usize {
         match self {
             Self::One(_) => 1,
@@ -113,7 +440,7 @@ impl<T: Clone + Default + PartialEq> OneMany<T> {
         }
     }
 
-    /// Returns `true` if the quota is an empty `Many`.
+    /// Returns `true` if the `OneMany` is an empty `Many`.
     pub const fn is_empty(&self) -> bool {
         match self {
             Self::One(_) => false,
@@ -121,3 +448,185 @@ impl<T: Clone + Default + PartialEq> OneMany<T> {
         }
     }
 }
+
+impl<T> IntoIterator for OneMany<T> {
+    type IntoIter = std::vec::IntoIter<T>;
+    type Item = T;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.to_vec().into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a OneMany<T> {
+    type IntoIter = slice::Iter<'a, T>;
+    type Item = &'a T;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut OneMany<T> {
+    type IntoIter = slice::IterMut<'a, T>;
+    type Item = &'a mut T;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Collects objects into a `Many`, whatever their number.
+impl<T> FromIterator<T> for OneMany<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::Many(iter.into_iter().collect())
+    }
+}
+
+impl<T> Extend<T> for OneMany<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.add(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn one_many() {
+        let mut types = OneMany::One("VerifiableCredential".to_string());
+        assert_eq!(types.first().map(String::as_str), Some("VerifiableCredential"));
+
+        types.extend(["EmployeeIDCredential".to_string()]);
+        assert!(types.contains(&"EmployeeIDCredential".to_string()));
+        assert_eq!(types.len(), 2);
+
+        types.retain(|t| t != "VerifiableCredential");
+        let lengths = types.map(|t| t.len());
+        assert_eq!(lengths, OneMany::Many(vec![20]));
+
+        let mut one = OneMany::One(1);
+        for n in &mut one {
+            *n += 1;
+        }
+        one.retain(|n| *n > 2);
+        assert!(one.is_empty());
+
+        let collected: OneMany<i32> = (1..=3).collect();
+        assert_eq!(collected.iter().sum::<i32>(), 6);
+        assert_eq!(collected.into_iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn kind() {
+        let kind = Kind::<u32>::from("https://issuer.example.com");
+        assert!(kind.is_string());
+        assert_eq!(kind.to_string(), "https://issuer.example.com");
+        assert_eq!(kind.as_ref().map(|n| n + 1), Kind::String("https://issuer.example.com".into()));
+        let kind = kind.try_into_object().expect_err("should be a string");
+        assert_eq!(
+            String::try_from(kind).expect("should be a string"),
+            "https://issuer.example.com"
+        );
+
+        let kind = Kind::Object(41).map(|n| n + 1);
+        assert!(kind.is_object());
+        assert_eq!(kind.to_string(), "42");
+        assert_eq!(kind.clone().into_string(), None);
+        assert_eq!(kind.into_object(), Some(42));
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    struct Issuer {
+        id: String,
+        display: Option<Vec<Display>>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    struct Display {
+        name: String,
+    }
+
+    #[test]
+    fn deserialize() {
+        let kind: Kind<Issuer> = serde_json::from_value(json!("https://issuer.example.com"))
+            .expect("should deserialize");
+        assert_eq!(kind.as_str(), Some("https://issuer.example.com"));
+
+        let err =
+            serde_json::from_value::<Kind<Issuer>>(json!({"id": 1})).expect_err("should fail");
+        assert_eq!(
+            err.to_string(),
+            "expected a string or an object: invalid value at `id`: invalid type: integer `1`, expected a string"
+        );
+
+        let many: OneMany<Issuer> =
+            serde_json::from_value(json!([{"id": "a"}, {"id": "b"}])).expect("should deserialize");
+        assert_eq!(many.len(), 2);
+
+        let err = serde_json::from_value::<OneMany<Issuer>>(json!([
+            {"id": "a"},
+            {"id": "b", "display": [{"title": "B"}]}
+        ]))
+        .expect_err("should fail");
+        assert_eq!(
+            err.to_string(),
+            "expected an array of objects: invalid value at `[1].display[0]`: missing field `name`"
+        );
+
+        let err = serde_json::from_value::<OneMany<Issuer>>(json!({})).expect_err("should fail");
+        assert_eq!(
+            err.to_string(),
+            "expected an object or an array of objects: missing field `id`"
+        );
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    struct Borrowed<'a> {
+        id: &'a str,
+    }
+
+    #[test]
+    fn borrowed() {
+        let kind: Kind<Borrowed<'_>> =
+            serde_json::from_str(r#"{"id": "x"}"#).expect("should deserialize");
+        assert_eq!(kind, Kind::Object(Borrowed { id: "x" }));
+
+        let many: OneMany<Borrowed<'_>> =
+            serde_json::from_str(r#"[{"id": "a"}, {"id": "b"}]"#).expect("should deserialize");
+        assert_eq!(many, OneMany::Many(vec![Borrowed { id: "a" }, Borrowed { id: "b" }]));
+    }
+
+    // An array is always read as `Many`, even when `T` could itself be read
+    // from an array.
+    #[test]
+    fn array_is_many() {
+        let many: OneMany<serde_json::Value> =
+            serde_json::from_value(json!(["a", "b"])).expect("should deserialize");
+        assert_eq!(many, OneMany::Many(vec![json!("a"), json!("b")]));
+
+        let many: OneMany<Vec<u8>> =
+            serde_json::from_value(json!([[1, 2], [3]])).expect("should deserialize");
+        assert_eq!(many, OneMany::Many(vec![vec![1, 2], vec![3]]));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        use std::collections::BTreeMap;
+
+        let claims = BTreeMap::from([(1_u8, "alice".to_string()), (2, "bob".to_string())]);
+        let value = OneMany::Many(vec![Kind::<BTreeMap<u8, String>>::Object(claims)]);
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).expect("should serialize");
+        let decoded: OneMany<Kind<BTreeMap<u8, String>>> =
+            ciborium::from_reader(bytes.as_slice()).expect("should deserialize");
+        assert_eq!(decoded, value);
+    }
+}
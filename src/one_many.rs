@@ -0,0 +1,390 @@
+//! # `OneMany`
+//!
+//! [`OneManySet`], a deduplicating [`OneMany`], and serde helpers controlling
+//! the shape of `OneMany` fields, for use with `#[serde(with = "...")]`.
+//!
+//! By default, `OneMany` serializes a `One` as a bare value and a `Many` as
+//! an array, whatever its length. Specifications such as the W3C Verifiable
+//! Credentials Data Model and OpenID for Verifiable Credentials are often
+//! stricter about the shape of fields like `@context` and `type`:
+//!
+//! ```rust,ignore
+//! #[derive(Deserialize, Serialize)]
+//! struct Credential {
+//!     #[serde(rename = "@context", with = "one_many::always_array")]
+//!     context: OneMany<Kind<Value>>,
+//!
+//!     #[serde(rename = "type", with = "one_many::collapse_single")]
+//!     type_: OneMany<String>,
+//! }
+//! ```
+
+use std::slice;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::OneMany;
+
+/// A [`OneMany`] with set semantics, for fields such as a credential's
+/// `type` where each value may appear only once.
+///
+/// Objects are kept in insertion order and serialized in the same shape as
+/// a `OneMany`. Duplicates are dropped when deserializing. Objects are
+/// compared using `PartialEq`, so operations are linear in the size of the
+/// set, which suits the small sets found in credentials.
+#[derive(Clone, Debug)]
+pub struct OneManySet<T>(OneMany<T>);
+
+impl<T> Default for OneManySet<T> {
+    fn default() -> Self {
+        Self(OneMany::Many(Vec::new()))
+    }
+}
+
+impl<T: PartialEq> OneManySet<T> {
+    /// Create a new, empty set.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an object to the set, returning `false` if it was already
+    /// present.
+    pub fn insert(&mut self, item: T) -> bool {
+        if self.contains(&item) {
+            return false;
+        }
+        self.0.add(item);
+        true
+    }
+
+    /// Returns `true` if the set contains an object equal to `item`.
+    pub fn contains(&self, item: &T) -> bool {
+        self.0.contains(item)
+    }
+
+    /// Returns `true` if every object in the set is also in `other`.
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.iter().all(|item| other.contains(item))
+    }
+
+    /// Returns `true` if the set contains every object in `other`.
+    pub fn is_superset(&self, other: &Self) -> bool {
+        other.is_subset(self)
+    }
+
+    /// Returns the objects in either set, in insertion order with objects
+    /// only in `other` last.
+    #[must_use]
+    pub fn union(&self, other: &Self) -> Self
+    where
+        T: Clone,
+    {
+        let mut union = self.clone();
+        union.extend(other.iter().cloned());
+        union
+    }
+
+    /// Returns the objects in both sets, in insertion order.
+    #[must_use]
+    pub fn intersection(&self, other: &Self) -> Self
+    where
+        T: Clone,
+    {
+        self.iter().filter(|item| other.contains(item)).cloned().collect()
+    }
+}
+
+impl<T> OneManySet<T> {
+    /// Returns the objects as a slice.
+    pub const fn as_slice(&self) -> &[T] {
+        self.0.as_slice()
+    }
+
+    /// Returns an iterator over the objects.
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.0.iter()
+    }
+
+    /// Returns the number of objects in the set.
+    pub const fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if the set is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the set as a `OneMany`.
+    pub fn into_inner(self) -> OneMany<T> {
+        self.0
+    }
+}
+
+/// Sets are equal if they contain the same objects, in any order.
+impl<T: PartialEq> PartialEq for OneManySet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.is_subset(other)
+    }
+}
+
+impl<T: Eq> Eq for OneManySet<T> {}
+
+/// Drops duplicates, keeping the first occurrence of each object.
+impl<T: PartialEq> From<OneMany<T>> for OneManySet<T> {
+    fn from(value: OneMany<T>) -> Self {
+        match value {
+            OneMany::One(one) => Self(OneMany::One(one)),
+            OneMany::Many(many) => many.into_iter().collect(),
+        }
+    }
+}
+
+impl<T> From<OneManySet<T>> for OneMany<T> {
+    fn from(value: OneManySet<T>) -> Self {
+        value.0
+    }
+}
+
+impl<T: PartialEq> FromIterator<T> for OneManySet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = Self(OneMany::Many(Vec::new()));
+        set.extend(iter);
+        set
+    }
+}
+
+impl<T: PartialEq> Extend<T> for OneManySet<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.insert(item);
+        }
+    }
+}
+
+impl<T> IntoIterator for OneManySet<T> {
+    type IntoIter = std::vec::IntoIter<T>;
+    type Item = T;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a OneManySet<T> {
+    type IntoIter = slice::Iter<'a, T>;
+    type Item = &'a T;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Serialize> Serialize for OneManySet<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de> + PartialEq> Deserialize<'de> for OneManySet<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        OneMany::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Serializes a `OneMany` as an array, regardless of variant.
+///
+/// Deserialization accepts only an array, returning a `Many`.
+pub mod always_array {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::OneMany;
+
+    /// Serialize the contents as an array.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an object cannot be serialized.
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &OneMany<T>, serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_slice().serialize(serializer)
+    }
+
+    /// Deserialize an array into a `Many`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is not an array of objects.
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OneMany<T>, D::Error> {
+        Vec::deserialize(deserializer).map(OneMany::Many)
+    }
+}
+
+/// Serializes a `OneMany` holding a single object as a bare value and
+/// anything else as an array.
+///
+/// Deserialization accepts either shape, returning a `One` for a bare value
+/// or a single-element array.
+pub mod collapse_single {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::OneMany;
+
+    /// Serialize a single object as a bare value and anything else as an
+    /// array.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an object cannot be serialized.
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &OneMany<T>, serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value.as_slice() {
+            [one] => one.serialize(serializer),
+            many => many.serialize(serializer),
+        }
+    }
+
+    /// Deserialize a bare value or an array, collapsing a single-element
+    /// array to a `One`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is neither an object nor an array of
+    /// objects.
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OneMany<T>, D::Error> {
+        match OneMany::deserialize(deserializer)? {
+            OneMany::Many(mut many) if many.len() == 1 => Ok(OneMany::One(many.remove(0))),
+            value => Ok(value),
+        }
+    }
+}
+
+/// Serializes a `OneMany` in its default shape, rejecting an empty `Many`.
+pub mod reject_empty {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+
+    use crate::OneMany;
+
+    /// Serialize the `OneMany`, failing if it is an empty `Many`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the `OneMany` is empty or an object cannot be
+    /// serialized.
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &OneMany<T>, serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if value.is_empty() {
+            return Err(<S::Error as ser::Error>::custom("expected at least one value"));
+        }
+        value.serialize(serializer)
+    }
+
+    /// Deserialize a bare value or an array, failing if the array is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is an empty array or is neither an
+    /// object nor an array of objects.
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OneMany<T>, D::Error> {
+        let value = OneMany::deserialize(deserializer)?;
+        if value.is_empty() {
+            return Err(<D::Error as de::Error>::custom("expected at least one value"));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Credential {
+        #[serde(rename = "@context", with = "always_array")]
+        context: OneMany<String>,
+
+        #[serde(rename = "type", with = "collapse_single")]
+        type_: OneMany<String>,
+
+        #[serde(with = "reject_empty")]
+        subject: OneMany<String>,
+    }
+
+    #[test]
+    fn shape() {
+        let credential = Credential {
+            context: OneMany::One("https://www.w3.org/ns/credentials/v2".to_string()),
+            type_: OneMany::Many(vec!["VerifiableCredential".to_string()]),
+            subject: OneMany::One("did:example:alice".to_string()),
+        };
+        let value = serde_json::to_value(&credential).expect("should serialize");
+        assert_eq!(
+            value,
+            json!({
+                "@context": ["https://www.w3.org/ns/credentials/v2"],
+                "type": "VerifiableCredential",
+                "subject": "did:example:alice"
+            })
+        );
+
+        let parsed: Credential = serde_json::from_value(value).expect("should deserialize");
+        assert_eq!(
+            parsed.context,
+            OneMany::Many(vec!["https://www.w3.org/ns/credentials/v2".to_string()])
+        );
+        assert_eq!(parsed.type_, OneMany::One("VerifiableCredential".to_string()));
+    }
+
+    #[test]
+    fn strict() {
+        let bare_context = json!({
+            "@context": "https://www.w3.org/ns/credentials/v2",
+            "type": [],
+            "subject": "alice"
+        });
+        serde_json::from_value::<Credential>(bare_context).expect_err("should require an array");
+
+        let empty_subject = json!({"@context": [], "type": [], "subject": []});
+        serde_json::from_value::<Credential>(empty_subject).expect_err("should reject empty");
+
+        let credential = Credential {
+            context: OneMany::Many(Vec::new()),
+            type_: OneMany::Many(Vec::new()),
+            subject: OneMany::Many(Vec::new()),
+        };
+        serde_json::to_value(&credential).expect_err("should reject empty");
+    }
+
+    #[test]
+    fn set() {
+        let mut types: OneManySet<String> =
+            serde_json::from_value(json!(["VerifiableCredential", "VerifiableCredential"]))
+                .expect("should deserialize");
+        assert_eq!(types.len(), 1);
+
+        assert!(types.insert("EmployeeIDCredential".to_string()));
+        assert!(!types.insert("VerifiableCredential".to_string()));
+
+        let requested = OneManySet::from(OneMany::One("EmployeeIDCredential".to_string()));
+        assert!(types.is_superset(&requested));
+        assert!(requested.is_subset(&types));
+        assert_eq!(types.intersection(&requested), requested);
+        assert_eq!(
+            requested.union(&types).as_slice(),
+            ["EmployeeIDCredential".to_string(), "VerifiableCredential".to_string()]
+        );
+        assert_eq!(requested.union(&types), types);
+    }
+}
@@ -0,0 +1,176 @@
+//! # Resolver
+//!
+//! Resolution of string references, such as an issuer URL or a DID, to the
+//! objects they identify, allowing [`Kind`](crate::Kind) fields holding
+//! either to be normalized to the object.
+//!
+//! When the `cache` feature is enabled, [`CachingResolver`] keeps resolved
+//! objects in a local LRU cache in front of another resolver.
+
+use std::future::Future;
+#[cfg(feature = "cache")]
+use std::num::NonZeroUsize;
+#[cfg(feature = "cache")]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use anyhow::Result;
+#[cfg(feature = "cache")]
+use chrono::{DateTime, TimeDelta, Utc};
+#[cfg(feature = "cache")]
+use lru::LruCache;
+
+#[cfg(feature = "cache")]
+use crate::clock::{Clock, SystemClock};
+
+// Cached objects along with the time they expire.
+#[cfg(feature = "cache")]
+type Cache<T> = LruCache<String, (T, DateTime<Utc>)>;
+
+/// The `Resolver` trait is implemented to resolve a string reference to the
+/// object it identifies.
+pub trait Resolver<T>: Send + Sync {
+    /// Resolve `reference` to an object.
+    fn resolve(&self, reference: &str) -> impl Future<Output = Result<T>> + Send;
+}
+
+/// A [`Resolver`] that caches objects resolved by the inner resolver.
+///
+/// Objects are cached for a fixed time-to-live and cloned on each read.
+/// Concurrent requests for a reference not yet cached are each passed to the
+/// inner resolver. Cloning the resolver is cheap and clones share the same
+/// cache.
+#[cfg(feature = "cache")]
+#[derive(Clone, Debug)]
+pub struct CachingResolver<R, T> {
+    inner: R,
+    cache: Arc<Mutex<Cache<T>>>,
+    ttl: TimeDelta,
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(feature = "cache")]
+impl<R: Resolver<T>, T: Clone + Send> CachingResolver<R, T> {
+    /// Wrap `inner` with a cache holding up to `capacity` objects, each for
+    /// `ttl`.
+    pub fn new(inner: R, capacity: NonZeroUsize, ttl: TimeDelta) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            ttl,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use the provided clock to determine when cached objects have expired.
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remove the cached object for a reference, if any.
+    pub fn invalidate(&self, reference: &str) {
+        self.cache().pop(reference);
+    }
+
+    /// Remove all cached objects.
+    pub fn clear(&self) {
+        self.cache().clear();
+    }
+
+    // Returns an unexpired cached object, evicting it if expired.
+    fn cached(&self, reference: &str) -> Option<T> {
+        let mut cache = self.cache();
+        match cache.get(reference) {
+            Some((_, expires_at)) if *expires_at < self.clock.now() => {
+                cache.pop(reference);
+                None
+            }
+            cached => cached.map(|(object, _)| object.clone()),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache<T>> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "cache")]
+impl<R: Resolver<T>, T: Clone + Send> Resolver<T> for CachingResolver<R, T> {
+    async fn resolve(&self, reference: &str) -> Result<T> {
+        if let Some(object) = self.cached(reference) {
+            return Ok(object);
+        }
+        let object = self.inner.resolve(reference).await?;
+        // saturate so very long ttls cache objects indefinitely
+        let expires_at =
+            self.clock.now().checked_add_signed(self.ttl).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.cache().put(reference.to_string(), (object.clone(), expires_at));
+        Ok(object)
+    }
+}
+
+#[cfg(all(test, feature = "cache"))]
+mod tests {
+    use std::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::Kind;
+    use crate::clock::MockClock;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct Issuer {
+        id: String,
+    }
+
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl Resolver<Issuer> for Counting {
+        fn resolve(&self, reference: &str) -> impl Future<Output = Result<Issuer>> + Send {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            future::ready(Ok(Issuer {
+                id: reference.to_string(),
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn caching() {
+        let clock = MockClock::default();
+        let resolver = CachingResolver::new(
+            Counting::default(),
+            NonZeroUsize::new(2).expect("should be non-zero"),
+            TimeDelta::minutes(5),
+        )
+        .with_clock(clock.clone());
+
+        let issuer = Kind::from("https://issuer.example.com");
+        let object = issuer.clone().resolve(&resolver).await.expect("should resolve");
+        assert_eq!(object.id, "https://issuer.example.com");
+        issuer.clone().resolve(&resolver).await.expect("should resolve");
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 1);
+
+        let embedded = Kind::Object(object.clone());
+        assert_eq!(embedded.resolve(&resolver).await.expect("should resolve"), object);
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 1);
+
+        clock.advance(TimeDelta::minutes(6));
+        issuer.resolve(&resolver).await.expect("should resolve");
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn long_ttl() {
+        let resolver = CachingResolver::new(
+            Counting::default(),
+            NonZeroUsize::new(1).expect("should be non-zero"),
+            TimeDelta::MAX,
+        );
+
+        resolver.resolve("https://issuer.example.com").await.expect("should resolve");
+        resolver.resolve("https://issuer.example.com").await.expect("should resolve");
+        assert_eq!(resolver.inner.0.load(Ordering::SeqCst), 1);
+    }
+}
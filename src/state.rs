@@ -1,10 +1,57 @@
 //! # State
 
-use anyhow::Result;
-use chrono::{DateTime, Utc};
+#[cfg(feature = "cache")]
+mod cached;
+mod capture;
+mod codec;
+mod dynamic;
+#[cfg(feature = "encryption")]
+mod encrypted;
+mod error;
+#[cfg(feature = "fs")]
+mod fs;
+mod lock;
+#[cfg(feature = "memory")]
+mod memory;
+mod scoped;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod watch;
+
+use std::future;
+
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "cache")]
+pub use self::cached::CachedStore;
+#[cfg(feature = "cbor")]
+pub use self::codec::Cbor;
+#[cfg(feature = "compression")]
+pub use self::codec::Compressed;
+#[cfg(feature = "msgpack")]
+pub use self::codec::MessagePack;
+pub use self::codec::{Codec, Json};
+pub use self::dynamic::{BoxFuture, DynStateStore, RawState};
+#[cfg(feature = "encryption")]
+pub use self::encrypted::{DerivedKeyring, EncryptedStore, Key, Keyring};
+pub use self::error::StateError;
+#[cfg(feature = "fs")]
+pub use self::fs::FileStore;
+pub use self::lock::{Lease, LockStore};
+#[cfg(feature = "memory")]
+pub use self::memory::InMemoryStore;
+pub use self::scoped::{Owner, Quota, ScopedStore};
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+pub use self::watch::{ChangeEvent, WatchStore};
+use crate::clock::{Clock, SystemClock};
+
+const SCAN_PAGE_SIZE: usize = 100;
+
 /// The `StateStore` trait is implemented to provide concrete storage and
 /// retrieval of retrieve server state between requests.
 pub trait StateStore: Send + Sync {
@@ -12,15 +59,197 @@ pub trait StateStore: Send + Sync {
     /// when data can be expunged from the state store.
     fn put<T: Serialize + Sync>(
         &self, owner: &str, key: &str, state: &State<T>,
-    ) -> impl Future<Output = Result<()>> + Send;
+    ) -> impl Future<Output = Result<(), StateError>> + Send;
 
     /// Retrieve data using the provided key.
+    ///
+    /// Returns [`StateError::NotFound`] if no state is stored for the key and
+    /// [`StateError::Expired`] if the stored state has expired.
     fn get<T: DeserializeOwned>(
         &self, owner: &str, key: &str,
-    ) -> impl Future<Output = Result<State<T>>> + Send;
+    ) -> impl Future<Output = Result<State<T>, StateError>> + Send;
 
     /// Remove data using the key provided.
-    fn purge(&self, owner: &str, key: &str) -> impl Future<Output = Result<()>> + Send;
+    fn purge(&self, owner: &str, key: &str) -> impl Future<Output = Result<(), StateError>> + Send;
+
+    /// Retrieve and remove state in a single step, for single-use values such
+    /// as pre-authorized codes and nonces.
+    ///
+    /// The default implementation calls [`StateStore::get`] followed by
+    /// [`StateStore::purge`] and so is not atomic. Stores able to perform
+    /// the operation atomically should override it.
+    fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<State<T>, StateError>> + Send {
+        async move {
+            let state = self.get(owner, key).await?;
+            self.purge(owner, key).await?;
+            Ok(state)
+        }
+    }
+
+    /// Store state only if the version currently stored matches
+    /// `state.version`, returning the new version on success.
+    ///
+    /// Missing or expired state is treated as version 0. Implementations must
+    /// perform the comparison and write atomically and store the state with
+    /// its version incremented, returning [`StateError::Conflict`] when the
+    /// versions do not match.
+    ///
+    /// The default implementation returns [`StateError::Unsupported`]. Stores
+    /// able to perform versioned writes should override it.
+    fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> impl Future<Output = Result<u64, StateError>> + Send {
+        let _ = (owner, key, state);
+        future::ready(Err(StateError::Unsupported("put_if_version")))
+    }
+
+    /// List the keys stored for an owner that start with `prefix`, in key
+    /// order, returning at most `limit` keys.
+    ///
+    /// Pass the cursor from the returned [`Page`] to fetch the next page.
+    /// Expired entries not yet removed from the store are included so
+    /// cleanup jobs can find them.
+    ///
+    /// The default implementation returns [`StateError::Unsupported`]. Stores
+    /// able to enumerate keys should override it.
+    fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> impl Future<Output = Result<Page, StateError>> + Send {
+        let _ = (owner, prefix, cursor, limit);
+        future::ready(Err(StateError::Unsupported("list")))
+    }
+
+    /// Return all keys stored for an owner that start with `prefix` by
+    /// walking every page returned by [`StateStore::list`].
+    fn scan(
+        &self, owner: &str, prefix: &str,
+    ) -> impl Future<Output = Result<Vec<KeyInfo>, StateError>> + Send {
+        async move {
+            let mut keys = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = self.list(owner, prefix, cursor.as_deref(), SCAN_PAGE_SIZE).await?;
+                keys.extend(page.keys);
+                if page.cursor.is_none() {
+                    return Ok(keys);
+                }
+                cursor = page.cursor;
+            }
+        }
+    }
+
+    /// Apply `f` to the state stored for the key, saving the result only if
+    /// the state has not been modified concurrently.
+    ///
+    /// Returns the updated state. Callers are responsible for retrying when
+    /// the update is rejected due to a version conflict.
+    fn update<T, F>(
+        &self, owner: &str, key: &str, f: F,
+    ) -> impl Future<Output = Result<State<T>, StateError>> + Send
+    where
+        T: Serialize + DeserializeOwned + Send + Sync,
+        F: FnOnce(&mut T) + Send,
+    {
+        async move {
+            let mut state = self.get::<T>(owner, key).await?;
+            f(&mut state.body);
+            state.version = self.put_if_version(owner, key, &state).await?;
+            Ok(state)
+        }
+    }
+
+    /// Retrieve state for several keys, returning results in the same order
+    /// as `keys`. Missing and expired state is returned as `None`.
+    ///
+    /// The default implementation calls [`StateStore::get`] for each key.
+    /// Stores able to fetch keys in a single round-trip should override it.
+    fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> impl Future<Output = Result<Vec<Option<State<T>>>, StateError>> + Send {
+        async move {
+            let mut states = Vec::with_capacity(keys.len());
+            for key in keys {
+                match self.get(owner, key).await {
+                    Ok(state) => states.push(Some(state)),
+                    Err(StateError::NotFound(_) | StateError::Expired(_)) => states.push(None),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(states)
+        }
+    }
+
+    /// Store state for several keys.
+    ///
+    /// The default implementation calls [`StateStore::put`] for each entry
+    /// and so is not atomic: entries written before a failure are kept.
+    /// Stores able to write entries in a single round-trip should override
+    /// it.
+    fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> impl Future<Output = Result<(), StateError>> + Send {
+        async move {
+            for (key, state) in entries {
+                self.put(owner, key, state).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Remove state for several keys.
+    ///
+    /// The default implementation calls [`StateStore::purge`] for each key.
+    /// Stores able to remove keys in a single round-trip should override it.
+    fn purge_many(
+        &self, owner: &str, keys: &[&str],
+    ) -> impl Future<Output = Result<(), StateError>> + Send {
+        async move {
+            for key in keys {
+                self.purge(owner, key).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Metadata for a stored key.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct KeyInfo {
+    /// The key state is stored under.
+    pub key: String,
+
+    /// Time the stored state expires.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A page of keys returned by [`StateStore::list`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Page {
+    /// Keys in this page, in key order.
+    pub keys: Vec<KeyInfo>,
+
+    /// Cursor used to fetch the next page, or `None` if this is the last
+    /// page.
+    pub cursor: Option<String>,
+}
+
+impl Page {
+    // Build a page from keys in key order, taking at most `limit` keys and
+    // setting the cursor when more keys remain.
+    #[cfg(any(feature = "fs", feature = "memory", feature = "sqlite"))]
+    pub(crate) fn from_sorted(keys: impl IntoIterator<Item = KeyInfo>, limit: usize) -> Self {
+        let limit = limit.max(1);
+        let mut keys = keys.into_iter().take(limit.saturating_add(1)).collect::<Vec<_>>();
+        let cursor = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().map(|info| info.key.clone())
+        } else {
+            None
+        };
+        Self { keys, cursor }
+    }
 }
 
 /// State is used to persist request information between issuance steps in the
@@ -32,21 +261,210 @@ pub struct State<T> {
 
     /// Time state should expire.
     pub expires_at: DateTime<Utc>,
+
+    /// The version of the stored state, incremented by the store on each
+    /// versioned write. Used to detect concurrent modification.
+    #[serde(default)]
+    pub version: u64,
 }
 
 impl<T> State<T> {
+    /// Create state that expires after the provided time-to-live, which may
+    /// be a named [`Expire`] policy or a [`TimeDelta`].
+    pub fn with_ttl(body: T, ttl: impl Into<Expire>) -> Self {
+        Self::with_ttl_with(body, ttl, &SystemClock)
+    }
+
+    /// Create state that expires after the provided time-to-live, measured
+    /// from the time given by `clock`.
+    pub fn with_ttl_with(body: T, ttl: impl Into<Expire>, clock: &(impl Clock + ?Sized)) -> Self {
+        Self {
+            body,
+            expires_at: expiry_after(clock.now(), ttl.into().duration()),
+            version: 0,
+        }
+    }
+
+    /// Create state that never expires.
+    pub const fn never_expires(body: T) -> Self {
+        Self {
+            body,
+            expires_at: NEVER,
+            version: 0,
+        }
+    }
+
     /// Determines whether state has expired or not.
     #[must_use]
     pub fn is_expired(&self) -> bool {
-        self.expires_at.signed_duration_since(Utc::now()).num_seconds() < 0
+        self.is_expired_with(&SystemClock)
+    }
+
+    /// Determines whether state has expired according to the provided clock.
+    #[must_use]
+    pub fn is_expired_with(&self, clock: &(impl Clock + ?Sized)) -> bool {
+        self.expires_at < clock.now()
+    }
+
+    /// Reset the expiry to the provided time-to-live from now.
+    pub fn refresh(&mut self, ttl: impl Into<Expire>) {
+        self.refresh_with(ttl, &SystemClock);
+    }
+
+    /// Reset the expiry to the provided time-to-live from the time given by
+    /// `clock`.
+    pub fn refresh_with(&mut self, ttl: impl Into<Expire>, clock: &(impl Clock + ?Sized)) {
+        self.expires_at = expiry_after(clock.now(), ttl.into().duration());
+    }
+
+    /// Push the current expiry out by the provided duration.
+    pub fn extend(&mut self, by: TimeDelta) {
+        self.expires_at = expiry_after(self.expires_at, by);
+    }
+
+    // A view of the state with the version replaced, allowing stores to
+    // serialize the next version without cloning the body.
+    #[cfg(any(feature = "fs", feature = "memory", feature = "sqlite"))]
+    pub(crate) const fn with_version(&self, version: u64) -> State<&T> {
+        State {
+            body: &self.body,
+            expires_at: self.expires_at,
+            version,
+        }
     }
 }
 
 impl<T: Serialize> From<T> for State<T> {
     fn from(body: T) -> Self {
-        Self {
-            body,
-            expires_at: Utc::now(), //+ Expire::Authorized.duration(),
+        Self::with_ttl(body, Expire::Authorized)
+    }
+}
+
+/// Expiry policies for state, providing named time-to-live durations for
+/// common kinds of state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expire {
+    /// State created during authorization, such as offers and authorization
+    /// codes. Expires after 5 minutes.
+    Authorized,
+
+    /// State associated with an issued access token. Expires after 15
+    /// minutes.
+    Access,
+
+    /// Nonces issued to clients. Expires after 10 minutes.
+    Nonce,
+
+    /// A custom time-to-live.
+    Custom(TimeDelta),
+}
+
+impl Expire {
+    /// The time-to-live for the policy.
+    #[must_use]
+    pub const fn duration(self) -> TimeDelta {
+        match self {
+            Self::Authorized => TimeDelta::minutes(5),
+            Self::Access => TimeDelta::minutes(15),
+            Self::Nonce => TimeDelta::minutes(10),
+            Self::Custom(ttl) => ttl,
         }
     }
 }
+
+impl From<TimeDelta> for Expire {
+    fn from(ttl: TimeDelta) -> Self {
+        Self::Custom(ttl)
+    }
+}
+
+// Expiry used for state that never expires (9999-12-31T23:59:59Z). Chosen
+// over `DateTime::MAX_UTC` so it can be represented by all stores.
+const NEVER: DateTime<Utc> = DateTime::from_timestamp(253_402_300_799, 0).expect("valid timestamp");
+
+// Add `ttl` to `from`, saturating at `NEVER`.
+fn expiry_after(from: DateTime<Utc>, ttl: TimeDelta) -> DateTime<Utc> {
+    from.checked_add_signed(ttl).map_or(NEVER, |expires_at| expires_at.min(NEVER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    // A store implementing only the required methods.
+    struct Minimal;
+
+    impl StateStore for Minimal {
+        fn put<T: Serialize + Sync>(
+            &self, _: &str, _: &str, _: &State<T>,
+        ) -> impl Future<Output = Result<(), StateError>> + Send {
+            future::ready(Ok(()))
+        }
+
+        fn get<T: DeserializeOwned>(
+            &self, _: &str, key: &str,
+        ) -> impl Future<Output = Result<State<T>, StateError>> + Send {
+            let err = StateError::NotFound(key.to_string());
+            async move { Err(err) }
+        }
+
+        fn purge(&self, _: &str, _: &str) -> impl Future<Output = Result<(), StateError>> + Send {
+            future::ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn optional() {
+        let err = Minimal
+            .put_if_version("owner", "key", &State::from("body"))
+            .await
+            .expect_err("should be unsupported");
+        assert!(matches!(err, StateError::Unsupported("put_if_version")));
+
+        let err = Minimal.scan("owner", "").await.expect_err("should be unsupported");
+        assert!(matches!(err, StateError::Unsupported("list")));
+    }
+
+    #[test]
+    fn from_body() {
+        let state = State::from("body");
+        assert!(!state.is_expired());
+        assert!(state.expires_at <= Utc::now() + Expire::Authorized.duration());
+    }
+
+    #[test]
+    fn ttl() {
+        let mut state = State::with_ttl("body", TimeDelta::seconds(-5));
+        assert!(state.is_expired());
+
+        state.refresh(Expire::Nonce);
+        assert!(!state.is_expired());
+
+        let expires_at = state.expires_at;
+        state.extend(TimeDelta::minutes(1));
+        assert_eq!(state.expires_at, expires_at + TimeDelta::minutes(1));
+    }
+
+    #[test]
+    fn clock() {
+        let clock = MockClock::default();
+        let mut state = State::with_ttl_with("body", Expire::Nonce, &clock);
+        assert_eq!(state.expires_at, clock.now() + Expire::Nonce.duration());
+        assert!(!state.is_expired_with(&clock));
+
+        clock.advance(Expire::Nonce.duration() + TimeDelta::milliseconds(500));
+        assert!(state.is_expired_with(&clock));
+
+        state.refresh_with(Expire::Nonce, &clock);
+        assert_eq!(state.expires_at, clock.now() + Expire::Nonce.duration());
+    }
+
+    #[test]
+    fn never_expires() {
+        let mut state = State::never_expires("body");
+        state.extend(TimeDelta::MAX);
+        assert!(!state.is_expired());
+        assert_eq!(state.expires_at, NEVER);
+    }
+}
@@ -0,0 +1,462 @@
+//! # Cached Store
+//!
+//! A [`StateStore`] decorator that keeps recently used state in a local LRU
+//! cache in front of a (typically remote) inner store.
+//!
+//! Reads are served from the cache when possible, falling through to the
+//! inner store on a miss. Writes go to the inner store first and are then
+//! cached. Entries are evicted once the cached state expires.
+//!
+//! The cache is local to the process: writes made by other processes to the
+//! inner store are not seen until the cached entry is evicted or
+//! invalidated.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use futures_core::Stream;
+use lru::LruCache;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::clock::{Clock, SystemClock};
+use crate::state::capture::Captured;
+use crate::state::{ChangeEvent, Page, State, StateError, StateStore, WatchStore};
+
+type CacheKey = (String, String);
+
+/// A [`StateStore`] with a read-through, write-through LRU cache.
+///
+/// Entries are cached in their JSON representation and deserialized to the
+/// requested type on each read. Bodies read from the inner store are
+/// captured as they are decoded by the inner store's codec, so state is
+/// never converted through an intermediate JSON value. Bodies JSON cannot
+/// represent, such as maps with composite keys or non-finite floats, are
+/// read from the inner store each time.
+///
+/// Cloning the store is cheap and clones share the same cache.
+#[derive(Clone, Debug)]
+pub struct CachedStore<S> {
+    inner: S,
+    cache: Arc<Mutex<Cache>>,
+    clock: Arc<dyn Clock>,
+}
+
+// Cached entries along with reads from the inner store in progress.
+#[derive(Debug)]
+struct Cache {
+    entries: LruCache<CacheKey, State<Vec<u8>>>,
+    fetches: HashMap<CacheKey, Fetch>,
+}
+
+// Reads of a key from the inner store in progress. The generation is
+// incremented each time the key is written or invalidated, so a read that
+// started before the change does not cache the superseded state.
+#[derive(Debug, Default)]
+struct Fetch {
+    pending: usize,
+    generation: u64,
+}
+
+impl Cache {
+    fn changed(&mut self, key: &CacheKey) {
+        if let Some(fetch) = self.fetches.get_mut(key) {
+            fetch.generation += 1;
+        }
+    }
+}
+
+impl<S: StateStore> CachedStore<S> {
+    /// Wrap `inner` with a cache holding up to `capacity` entries.
+    pub fn new(inner: S, capacity: NonZeroUsize) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(Cache {
+                entries: LruCache::new(capacity),
+                fetches: HashMap::new(),
+            })),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use the provided clock to determine when cached entries have expired.
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remove the cached entry for a key, if any.
+    pub fn invalidate(&self, owner: &str, key: &str) {
+        let key = cache_key(owner, key);
+        let mut cache = self.cache();
+        cache.entries.pop(&key);
+        cache.changed(&key);
+    }
+
+    /// Remove all cached entries.
+    pub fn clear(&self) {
+        let mut cache = self.cache();
+        cache.entries.clear();
+        for fetch in cache.fetches.values_mut() {
+            fetch.generation += 1;
+        }
+    }
+
+    /// Remove expired entries from the cache, returning the number removed.
+    pub fn evict_expired(&self) -> usize {
+        let now = self.clock.now();
+        let mut cache = self.cache();
+        let expired = cache
+            .entries
+            .iter()
+            .filter(|(_, state)| state.expires_at < now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &expired {
+            cache.entries.pop(key);
+        }
+        drop(cache);
+        expired.len()
+    }
+
+    // Returns an unexpired cached entry decoded to `T`, evicting the entry
+    // if it has expired or cannot be decoded.
+    fn cached<T: DeserializeOwned>(&self, owner: &str, key: &str) -> Option<State<T>> {
+        let key = cache_key(owner, key);
+        let mut cache = self.cache();
+        let state = cache.entries.get(&key)?;
+        if state.expires_at >= self.clock.now()
+            && let Ok(body) = serde_json::from_slice(&state.body)
+        {
+            return Some(State {
+                body,
+                expires_at: state.expires_at,
+                version: state.version,
+            });
+        }
+        cache.entries.pop(&key);
+        None
+    }
+
+    // Cache the JSON representation of state written to the inner store,
+    // or invalidate the key if the body cannot be represented as JSON.
+    fn insert<T: Serialize>(&self, owner: &str, key: &str, state: &State<T>, version: u64) {
+        let Ok(body) = serde_json::to_vec(&state.body) else {
+            self.invalidate(owner, key);
+            return;
+        };
+        let key = cache_key(owner, key);
+        let mut cache = self.cache();
+        cache.changed(&key);
+        cache.entries.put(
+            key,
+            State {
+                body,
+                expires_at: state.expires_at,
+                version,
+            },
+        );
+    }
+
+    // Register a read of the key from the inner store.
+    fn fetch(&self, owner: &str, key: &str) -> FetchGuard<'_> {
+        let key = cache_key(owner, key);
+        let mut cache = self.cache();
+        let fetch = cache.fetches.entry(key.clone()).or_default();
+        fetch.pending += 1;
+        let generation = fetch.generation;
+        drop(cache);
+        FetchGuard {
+            cache: &self.cache,
+            key,
+            generation,
+        }
+    }
+
+    // Cache state read from the inner store, unless the key has been
+    // written or invalidated since the read started.
+    fn complete<T>(&self, fetch: &FetchGuard<'_>, state: State<Captured<T>>) -> State<T> {
+        if let Some(json) = state.body.json {
+            let mut cache = self.cache();
+            if cache.fetches.get(&fetch.key).is_some_and(|f| f.generation == fetch.generation) {
+                cache.entries.put(
+                    fetch.key.clone(),
+                    State {
+                        body: json,
+                        expires_at: state.expires_at,
+                        version: state.version,
+                    },
+                );
+            }
+        }
+        State {
+            body: state.body.value,
+            expires_at: state.expires_at,
+            version: state.version,
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        lock(&self.cache)
+    }
+}
+
+// Deregisters a read from the inner store when dropped, including when the
+// read is cancelled.
+struct FetchGuard<'a> {
+    cache: &'a Mutex<Cache>,
+    key: CacheKey,
+    generation: u64,
+}
+
+impl Drop for FetchGuard<'_> {
+    fn drop(&mut self) {
+        let mut cache = lock(self.cache);
+        if let Some(fetch) = cache.fetches.get_mut(&self.key) {
+            fetch.pending -= 1;
+            if fetch.pending == 0 {
+                cache.fetches.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<S: StateStore> StateStore for CachedStore<S> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        if let Err(e) = self.inner.put(owner, key, state).await {
+            self.invalidate(owner, key);
+            return Err(e);
+        }
+        self.insert(owner, key, state, state.version);
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        if let Some(state) = self.cached(owner, key) {
+            return Ok(state);
+        }
+        let fetch = self.fetch(owner, key);
+        let state = self.inner.get::<Captured<T>>(owner, key).await?;
+        Ok(self.complete(&fetch, state))
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        let result = self.inner.purge(owner, key).await;
+        self.invalidate(owner, key);
+        result
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let result = self.inner.take(owner, key).await;
+        self.invalidate(owner, key);
+        result
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        let version = match self.inner.put_if_version(owner, key, state).await {
+            Ok(version) => version,
+            Err(e) => {
+                self.invalidate(owner, key);
+                return Err(e);
+            }
+        };
+        self.insert(owner, key, state, version);
+        Ok(version)
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        self.inner.list(owner, prefix, cursor, limit).await
+    }
+
+    async fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        let mut states = keys.iter().map(|key| self.cached(owner, key)).collect::<Vec<_>>();
+
+        // fetch all misses from the inner store in a single batch
+        let misses = keys
+            .iter()
+            .zip(&states)
+            .filter(|(_, state)| state.is_none())
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        if misses.is_empty() {
+            return Ok(states);
+        }
+
+        let guards = misses.iter().map(|key| self.fetch(owner, key)).collect::<Vec<_>>();
+        let mut fetched = self
+            .inner
+            .get_many::<Captured<T>>(owner, &misses)
+            .await?
+            .into_iter()
+            .zip(&guards)
+            .map(|(state, fetch)| state.map(|state| self.complete(fetch, state)));
+        for state in states.iter_mut().filter(|state| state.is_none()) {
+            *state = fetched.next().flatten();
+        }
+        Ok(states)
+    }
+
+    async fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        if let Err(e) = self.inner.put_many(owner, entries).await {
+            for (key, _) in entries {
+                self.invalidate(owner, key);
+            }
+            return Err(e);
+        }
+        for (key, state) in entries {
+            self.insert(owner, key, state, state.version);
+        }
+        Ok(())
+    }
+
+    async fn purge_many(&self, owner: &str, keys: &[&str]) -> Result<(), StateError> {
+        let result = self.inner.purge_many(owner, keys).await;
+        for key in keys {
+            self.invalidate(owner, key);
+        }
+        result
+    }
+}
+
+impl<S: WatchStore> WatchStore for CachedStore<S> {
+    fn watch(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<impl Stream<Item = ChangeEvent> + Send + 'static, StateError>> + Send
+    {
+        self.inner.watch(owner, key)
+    }
+}
+
+fn cache_key(owner: &str, key: &str) -> CacheKey {
+    (owner.to_string(), key.to_string())
+}
+
+fn lock(cache: &Mutex<Cache>) -> MutexGuard<'_, Cache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::state::InMemoryStore;
+
+    fn store(inner: &InMemoryStore) -> CachedStore<InMemoryStore> {
+        CachedStore::new(inner.clone(), NonZeroUsize::new(2).expect("should be non-zero"))
+    }
+
+    #[tokio::test]
+    async fn read_through() {
+        let inner = InMemoryStore::new();
+        let store = store(&inner);
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        inner.put("owner", "key", &alice).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get"), alice);
+
+        // served from the cache until invalidated
+        inner.purge("owner", "key").await.expect("should purge");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get"), alice);
+        store.invalidate("owner", "key");
+        store.get::<String>("owner", "key").await.expect_err("should miss");
+    }
+
+    #[tokio::test]
+    async fn write_through() {
+        let inner = InMemoryStore::new();
+        let store = store(&inner);
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        store.put("owner", "key", &alice).await.expect("should put");
+        assert_eq!(inner.get::<String>("owner", "key").await.expect("should get"), alice);
+
+        let version = store.put_if_version("owner", "key", &alice).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get").version, version);
+
+        store.purge("owner", "key").await.expect("should purge");
+        store.get::<String>("owner", "key").await.expect_err("should be purged");
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let clock = MockClock::default();
+        let inner = InMemoryStore::new();
+        let store = store(&inner).with_clock(clock.clone());
+
+        store
+            .put("owner", "key", &State::with_ttl("alice".to_string(), TimeDelta::minutes(5)))
+            .await
+            .expect("should put");
+        clock.advance(TimeDelta::minutes(10));
+
+        assert_eq!(store.evict_expired(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalidated_fetch() {
+        let inner = InMemoryStore::new();
+        let store = store(&inner);
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+        inner.put("owner", "key", &alice).await.expect("should put");
+
+        // the key is taken while it is being read from the inner store
+        let fetch = store.fetch("owner", "key");
+        let state = inner.get::<Captured<String>>("owner", "key").await.expect("should get");
+        store.take::<String>("owner", "key").await.expect("should take");
+        assert_eq!(store.complete(&fetch, state), alice);
+        drop(fetch);
+
+        store.get::<String>("owner", "key").await.expect_err("should not be cached");
+        assert!(store.cache().fetches.is_empty());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn codec() {
+        use std::collections::BTreeMap;
+
+        use crate::state::Cbor;
+
+        let inner = InMemoryStore::new().with_codec(Cbor);
+        let store = CachedStore::new(inner.clone(), NonZeroUsize::MIN);
+        let state = State::with_ttl(BTreeMap::from([(1_u8, u128::MAX)]), TimeDelta::minutes(5));
+
+        inner.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(
+            store.get::<BTreeMap<u8, u128>>("owner", "key").await.expect("should get"),
+            state
+        );
+
+        // served from the cache
+        inner.purge("owner", "key").await.expect("should purge");
+        assert_eq!(
+            store.get::<BTreeMap<u8, u128>>("owner", "key").await.expect("should get"),
+            state
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn conformance() {
+        crate::state::testing::conformance(store(&InMemoryStore::new())).await;
+    }
+}
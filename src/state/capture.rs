@@ -0,0 +1,533 @@
+//! # Capture
+//!
+//! Records the JSON representation of a value while it is deserialized from
+//! another format, allowing the [`DynStateStore`](crate::state::DynStateStore)
+//! adapter and the cached store to obtain JSON for state read from a store
+//! without knowing the store's codec or requiring the body to implement
+//! `Serialize`.
+//!
+//! The value is deserialized exactly as it would be without capture: every
+//! call made by the value's `Deserialize` implementation is forwarded to the
+//! underlying deserializer, so type hints such as `deserialize_u128` are
+//! preserved. Values JSON cannot represent, such as non-finite floats or
+//! maps with composite keys, leave the capture empty rather than failing.
+
+use std::fmt;
+
+use serde::de::{
+    DeserializeSeed, Deserializer, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
+use serde::{Deserialize, Serialize};
+
+/// A value along with its JSON representation, if it has one.
+pub struct Captured<T> {
+    /// The deserialized value.
+    #[cfg_attr(not(feature = "cache"), allow(dead_code))]
+    pub value: T,
+
+    /// The JSON representation of the value, or `None` if it cannot be
+    /// represented as JSON.
+    pub json: Option<Vec<u8>>,
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Captured<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut recorder = Recorder::default();
+        let value = T::deserialize(Tee {
+            de: deserializer,
+            rec: &mut recorder,
+            key: false,
+        })?;
+        Ok(Self {
+            value,
+            json: (!recorder.failed).then_some(recorder.json),
+        })
+    }
+}
+
+// JSON written as a value is deserialized.
+#[derive(Default)]
+struct Recorder {
+    json: Vec<u8>,
+    failed: bool,
+}
+
+impl Recorder {
+    fn push(&mut self, byte: u8) {
+        self.json.push(byte);
+    }
+
+    // Write a scalar, quoting it when used as a map key.
+    fn scalar<T: Serialize + fmt::Display>(&mut self, value: &T, key: bool) {
+        if key {
+            self.string(&value.to_string());
+        } else if serde_json::to_writer(&mut self.json, value).is_err() {
+            self.failed = true;
+        }
+    }
+
+    fn float<T: Serialize + fmt::Display>(&mut self, value: &T, finite: bool, key: bool) {
+        if finite {
+            self.scalar(value, key);
+        } else {
+            self.failed = true;
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        if serde_json::to_writer(&mut self.json, value).is_err() {
+            self.failed = true;
+        }
+    }
+
+    fn bytes(&mut self, value: &[u8], key: bool) {
+        if key || serde_json::to_writer(&mut self.json, value).is_err() {
+            self.failed = true;
+        }
+    }
+
+    // Write `null`, which cannot be used as a map key.
+    fn null(&mut self, key: bool) {
+        self.failed |= key;
+        self.json.extend_from_slice(b"null");
+    }
+}
+
+// A deserializer recording values produced by the wrapped deserializer.
+struct Tee<'r, D> {
+    de: D,
+    rec: &'r mut Recorder,
+    key: bool,
+}
+
+macro_rules! forward {
+    ($($method:ident($($arg:ident: $ty:ty),*)),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(
+                self, $($arg: $ty,)* visitor: V,
+            ) -> Result<V::Value, Self::Error> {
+                self.de.$method($($arg,)* TeeVisitor { visitor, rec: self.rec, key: self.key })
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Tee<'_, D> {
+    type Error = D::Error;
+
+    forward! {
+        deserialize_any(), deserialize_bool(), deserialize_i8(), deserialize_i16(),
+        deserialize_i32(), deserialize_i64(), deserialize_i128(), deserialize_u8(),
+        deserialize_u16(), deserialize_u32(), deserialize_u64(), deserialize_u128(),
+        deserialize_f32(), deserialize_f64(), deserialize_char(), deserialize_str(),
+        deserialize_string(), deserialize_bytes(), deserialize_byte_buf(),
+        deserialize_option(), deserialize_unit(), deserialize_seq(), deserialize_map(),
+        deserialize_identifier(),
+        deserialize_unit_struct(name: &'static str),
+        deserialize_newtype_struct(name: &'static str),
+        deserialize_tuple(len: usize),
+        deserialize_tuple_struct(name: &'static str, len: usize),
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]),
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]),
+    }
+
+    // ignored values are still recorded so the capture is complete
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.de.deserialize_any(TeeVisitor {
+            visitor,
+            rec: self.rec,
+            key: self.key,
+        })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.de.is_human_readable()
+    }
+}
+
+// Records each value visited before passing it to the wrapped visitor.
+struct TeeVisitor<'r, V> {
+    visitor: V,
+    rec: &'r mut Recorder,
+    key: bool,
+}
+
+macro_rules! visit_scalar {
+    ($($method:ident($ty:ty)),* $(,)?) => {
+        $(
+            fn $method<E: serde::de::Error>(self, v: $ty) -> Result<Self::Value, E> {
+                self.rec.scalar(&v, self.key);
+                self.visitor.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for TeeVisitor<'_, V> {
+    type Value = V::Value;
+
+    visit_scalar! {
+        visit_bool(bool), visit_i8(i8), visit_i16(i16), visit_i32(i32), visit_i64(i64),
+        visit_i128(i128), visit_u8(u8), visit_u16(u16), visit_u32(u32), visit_u64(u64),
+        visit_u128(u128), visit_char(char),
+    }
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.visitor.expecting(formatter)
+    }
+
+    fn visit_f32<E: serde::de::Error>(self, v: f32) -> Result<Self::Value, E> {
+        self.rec.float(&v, v.is_finite(), self.key);
+        self.visitor.visit_f32(v)
+    }
+
+    fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        self.rec.float(&v, v.is_finite(), self.key);
+        self.visitor.visit_f64(v)
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        self.rec.string(v);
+        self.visitor.visit_str(v)
+    }
+
+    fn visit_borrowed_str<E: serde::de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+        self.rec.string(v);
+        self.visitor.visit_borrowed_str(v)
+    }
+
+    fn visit_string<E: serde::de::Error>(self, v: String) -> Result<Self::Value, E> {
+        self.rec.string(&v);
+        self.visitor.visit_string(v)
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        self.rec.bytes(v, self.key);
+        self.visitor.visit_bytes(v)
+    }
+
+    fn visit_borrowed_bytes<E: serde::de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+        self.rec.bytes(v, self.key);
+        self.visitor.visit_borrowed_bytes(v)
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        self.rec.bytes(&v, self.key);
+        self.visitor.visit_byte_buf(v)
+    }
+
+    fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        self.rec.null(self.key);
+        self.visitor.visit_none()
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        self.rec.null(self.key);
+        self.visitor.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        self.visitor.visit_some(Tee {
+            de: deserializer,
+            rec: self.rec,
+            key: self.key,
+        })
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self, deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        self.visitor.visit_newtype_struct(Tee {
+            de: deserializer,
+            rec: self.rec,
+            key: self.key,
+        })
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let rec = self.rec;
+        rec.failed |= self.key;
+        rec.push(b'[');
+        let value = self.visitor.visit_seq(TeeSeq {
+            seq,
+            rec: &mut *rec,
+            first: true,
+        })?;
+        rec.push(b']');
+        Ok(value)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let rec = self.rec;
+        rec.failed |= self.key;
+        rec.push(b'{');
+        let value = self.visitor.visit_map(TeeMap {
+            map,
+            rec: &mut *rec,
+            first: true,
+        })?;
+        rec.push(b'}');
+        Ok(value)
+    }
+
+    // Enums are recorded in serde's externally tagged JSON form: a unit
+    // variant as its name and any other variant as `{"name": content}`.
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        self.rec.failed |= self.key;
+        self.visitor.visit_enum(TeeEnum { data, rec: self.rec })
+    }
+}
+
+// Passes a `Tee` to the wrapped seed.
+struct TeeSeed<'r, S> {
+    seed: S,
+    rec: &'r mut Recorder,
+    key: bool,
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for TeeSeed<'_, S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<S::Value, D::Error> {
+        self.seed.deserialize(Tee {
+            de: deserializer,
+            rec: self.rec,
+            key: self.key,
+        })
+    }
+}
+
+struct TeeSeq<'r, A> {
+    seq: A,
+    rec: &'r mut Recorder,
+    first: bool,
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for TeeSeq<'_, A> {
+    type Error = A::Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self, seed: S,
+    ) -> Result<Option<S::Value>, A::Error> {
+        // the separator is removed again if the sequence has ended
+        let mark = self.rec.json.len();
+        if !self.first {
+            self.rec.push(b',');
+        }
+        let seed = TeeSeed {
+            seed,
+            rec: &mut *self.rec,
+            key: false,
+        };
+        let Some(element) = self.seq.next_element_seed(seed)? else {
+            self.rec.json.truncate(mark);
+            return Ok(None);
+        };
+        self.first = false;
+        Ok(Some(element))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.seq.size_hint()
+    }
+}
+
+struct TeeMap<'r, A> {
+    map: A,
+    rec: &'r mut Recorder,
+    first: bool,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for TeeMap<'_, A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self, seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        let mark = self.rec.json.len();
+        if !self.first {
+            self.rec.push(b',');
+        }
+        let seed = TeeSeed {
+            seed,
+            rec: &mut *self.rec,
+            key: true,
+        };
+        let Some(key) = self.map.next_key_seed(seed)? else {
+            self.rec.json.truncate(mark);
+            return Ok(None);
+        };
+        self.rec.push(b':');
+        self.first = false;
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, A::Error> {
+        self.map.next_value_seed(TeeSeed {
+            seed,
+            rec: &mut *self.rec,
+            key: false,
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.map.size_hint()
+    }
+}
+
+struct TeeEnum<'r, A> {
+    data: A,
+    rec: &'r mut Recorder,
+}
+
+impl<'de, 'r, A: EnumAccess<'de>> EnumAccess<'de> for TeeEnum<'r, A> {
+    type Error = A::Error;
+    type Variant = TeeVariant<'r, A::Variant>;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(
+        self, seed: S,
+    ) -> Result<(S::Value, Self::Variant), A::Error> {
+        // written as `{"name":` and trimmed back to `"name"` for unit variants
+        let mark = self.rec.json.len();
+        self.rec.push(b'{');
+        let seed = TeeSeed {
+            seed,
+            rec: &mut *self.rec,
+            key: true,
+        };
+        let (variant, access) = self.data.variant_seed(seed)?;
+        self.rec.push(b':');
+        Ok((
+            variant,
+            TeeVariant {
+                access,
+                rec: self.rec,
+                mark,
+            },
+        ))
+    }
+}
+
+struct TeeVariant<'r, A> {
+    access: A,
+    rec: &'r mut Recorder,
+    mark: usize,
+}
+
+impl<'de, A: VariantAccess<'de>> VariantAccess<'de> for TeeVariant<'_, A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.rec.json.remove(self.mark);
+        self.rec.json.pop();
+        self.access.unit_variant()
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, A::Error> {
+        let value = self.access.newtype_variant_seed(TeeSeed {
+            seed,
+            rec: &mut *self.rec,
+            key: false,
+        })?;
+        self.rec.push(b'}');
+        Ok(value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        let value = self.access.tuple_variant(
+            len,
+            TeeVisitor {
+                visitor,
+                rec: &mut *self.rec,
+                key: false,
+            },
+        )?;
+        self.rec.push(b'}');
+        Ok(value)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self, fields: &'static [&'static str], visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        let value = self.access.struct_variant(
+            fields,
+            TeeVisitor {
+                visitor,
+                rec: &mut *self.rec,
+                key: false,
+            },
+        )?;
+        self.rec.push(b'}');
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    enum Grant {
+        Code,
+        PreAuthorized { tx_code: Option<String> },
+        Scopes(Vec<String>),
+    }
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Body {
+        grants: Vec<Grant>,
+        counts: BTreeMap<u8, u128>,
+        data: Vec<u8>,
+    }
+
+    fn capture<T: for<'de> Deserialize<'de>>(json: &str) -> Captured<T> {
+        let mut de = serde_json::Deserializer::from_str(json);
+        Captured::deserialize(&mut de).expect("should deserialize")
+    }
+
+    #[test]
+    fn round_trip() {
+        let body = Body {
+            grants: vec![
+                Grant::Code,
+                Grant::PreAuthorized { tx_code: None },
+                Grant::Scopes(vec!["openid".to_string()]),
+            ],
+            counts: BTreeMap::from([(1, u128::MAX)]),
+            data: vec![0, 255],
+        };
+        let json = serde_json::to_string(&body).expect("should serialize");
+
+        let captured = capture::<Body>(&json);
+        assert_eq!(captured.value, body);
+        let recorded = captured.json.expect("should record");
+        assert_eq!(serde_json::from_slice::<Body>(&recorded).expect("should decode"), body);
+    }
+
+    #[test]
+    fn ignored() {
+        let captured =
+            capture::<Grant>(r#"{"PreAuthorized": {"tx_code": "1234", "extra": [1, {}]}}"#);
+        let recorded = captured.json.expect("should record");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&recorded).expect("should decode"),
+            json!({"PreAuthorized": {"tx_code": "1234", "extra": [1, {}]}})
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn not_representable() {
+        let mut data = Vec::new();
+        ciborium::into_writer(&BTreeMap::from([(vec![1_u8], f64::NAN)]), &mut data)
+            .expect("should encode");
+        let captured: Captured<BTreeMap<Vec<u8>, f64>> =
+            ciborium::from_reader(data.as_slice()).expect("should decode");
+        assert_eq!(captured.value.len(), 1);
+        assert!(captured.json.is_none());
+    }
+}
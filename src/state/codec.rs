@@ -0,0 +1,181 @@
+//! # Codec
+//!
+//! Codecs convert state to and from the bytes persisted by a store, allowing
+//! the wire format to be chosen independently of the storage backend.
+//!
+//! [`Json`] is always available and is the default for all stores. [`Cbor`]
+//! and [`MessagePack`] are enabled with the `cbor` and `msgpack` features,
+//! and [`Compressed`] (feature `compression`) wraps any codec to deflate its
+//! output.
+
+use std::fmt::Debug;
+
+use anyhow::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// The `Codec` trait is implemented to serialize state for storage.
+pub trait Codec: Clone + Debug + Send + Sync + 'static {
+    /// Serialize a value to bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value cannot be serialized.
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Deserialize a value from bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bytes cannot be deserialized to `T`.
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+/// Serializes state as JSON.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// Serializes state as CBOR ([RFC 8949]).
+///
+/// [RFC 8949]: https://www.rfc-editor.org/rfc/rfc8949
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::into_writer(value, &mut data)?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(ciborium::from_reader(data)?)
+    }
+}
+
+/// Serializes state as MessagePack.
+///
+/// Structs are written as maps keyed by field name so fields can be added to
+/// state bodies without breaking existing entries.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// Compresses the output of another codec using DEFLATE.
+#[cfg(feature = "compression")]
+#[derive(Clone, Copy, Debug)]
+pub struct Compressed<C = Json> {
+    codec: C,
+    level: flate2::Compression,
+}
+
+#[cfg(feature = "compression")]
+impl<C: Codec> Compressed<C> {
+    /// Compress the output of `codec` using the default compression level.
+    pub fn new(codec: C) -> Self {
+        Self {
+            codec,
+            level: flate2::Compression::default(),
+        }
+    }
+
+    /// Use the provided compression level, from 0 (none) to 9 (best).
+    #[must_use]
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = flate2::Compression::new(level.min(9));
+        self
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<C: Codec + Default> Default for Compressed<C> {
+    fn default() -> Self {
+        Self::new(C::default())
+    }
+}
+
+#[cfg(feature = "compression")]
+impl<C: Codec> Codec for Compressed<C> {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+        encoder.write_all(&self.codec.encode(value)?)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        use std::io::Read;
+
+        let mut decoded = Vec::new();
+        flate2::read::DeflateDecoder::new(data).read_to_end(&mut decoded)?;
+        self.codec.decode(&decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::state::State;
+
+    fn round_trip(codec: &impl Codec) {
+        let state =
+            State::with_ttl(json!({"name": "alice", "scopes": ["a", "b"]}), TimeDelta::minutes(5));
+        let data = codec.encode(&state).expect("should encode");
+        assert_eq!(codec.decode::<State<Value>>(&data).expect("should decode"), state);
+        codec.decode::<State<u64>>(&data).expect_err("should reject mismatched body");
+    }
+
+    #[test]
+    fn json() {
+        round_trip(&Json);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        round_trip(&Cbor);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack() {
+        round_trip(&MessagePack);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed() {
+        round_trip(&Compressed::new(Json).with_level(9));
+
+        let body = "a".repeat(4096);
+        let data = Compressed::new(Json).encode(&body).expect("should encode");
+        assert!(data.len() < Json.encode(&body).expect("should encode").len() / 10);
+    }
+}
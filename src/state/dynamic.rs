@@ -0,0 +1,312 @@
+//! # Dynamic Store
+//!
+//! An object-safe counterpart to [`StateStore`], allowing stores to be held
+//! as `Arc<dyn DynStateStore>` rather than threaded through providers as a
+//! generic parameter.
+//!
+//! State bodies cross the trait object boundary as JSON-serialized bytes,
+//! so only bodies with a JSON representation are supported: map keys must
+//! be strings or scalars, which are written as strings, integers must fit
+//! in 64 bits, and floats must be finite. Bodies written through
+//! `Arc<dyn DynStateStore>` or read from the wrapped store that fall
+//! outside this are rejected with [`StateError::Internal`] rather than
+//! being altered.
+//!
+//! Every [`StateStore`] implements [`DynStateStore`], and
+//! `Arc<dyn DynStateStore>` implements [`StateStore`], so the two can be
+//! used interchangeably:
+//!
+//! ```rust,ignore
+//! let store: Arc<dyn DynStateStore> = Arc::new(InMemoryStore::new());
+//! store.put("owner", "key", &State::from(body)).await?;
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_json::Value;
+
+use crate::state::capture::Captured;
+use crate::state::{Page, State, StateError, StateStore};
+
+/// A boxed future returned by [`DynStateStore`] methods.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// State with a JSON-serialized body, as passed to and from
+/// [`DynStateStore`] methods.
+pub type RawState = State<Vec<u8>>;
+
+/// An object-safe version of [`StateStore`] operating on JSON-serialized
+/// state bodies.
+///
+/// Methods are prefixed with `dyn_` so they do not clash with
+/// [`StateStore`] methods when both traits are in scope. See the
+/// corresponding [`StateStore`] methods for details of each operation.
+pub trait DynStateStore: Send + Sync {
+    /// Store state using the provided key.
+    fn dyn_put<'a>(
+        &'a self, owner: &'a str, key: &'a str, state: &'a RawState,
+    ) -> BoxFuture<'a, Result<(), StateError>>;
+
+    /// Retrieve state using the provided key.
+    fn dyn_get<'a>(
+        &'a self, owner: &'a str, key: &'a str,
+    ) -> BoxFuture<'a, Result<RawState, StateError>>;
+
+    /// Remove state using the provided key.
+    fn dyn_purge<'a>(
+        &'a self, owner: &'a str, key: &'a str,
+    ) -> BoxFuture<'a, Result<(), StateError>>;
+
+    /// Retrieve and remove state in a single step.
+    fn dyn_take<'a>(
+        &'a self, owner: &'a str, key: &'a str,
+    ) -> BoxFuture<'a, Result<RawState, StateError>>;
+
+    /// Store state only if the stored version matches `state.version`.
+    fn dyn_put_if_version<'a>(
+        &'a self, owner: &'a str, key: &'a str, state: &'a RawState,
+    ) -> BoxFuture<'a, Result<u64, StateError>>;
+
+    /// List the keys stored for an owner that start with `prefix`.
+    fn dyn_list<'a>(
+        &'a self, owner: &'a str, prefix: &'a str, cursor: Option<&'a str>, limit: usize,
+    ) -> BoxFuture<'a, Result<Page, StateError>>;
+
+    /// Retrieve state for several keys.
+    fn dyn_get_many<'a>(
+        &'a self, owner: &'a str, keys: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<Option<RawState>>, StateError>>;
+
+    /// Store state for several keys.
+    fn dyn_put_many<'a>(
+        &'a self, owner: &'a str, entries: &'a [(&'a str, RawState)],
+    ) -> BoxFuture<'a, Result<(), StateError>>;
+
+    /// Remove state for several keys.
+    fn dyn_purge_many<'a>(
+        &'a self, owner: &'a str, keys: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<(), StateError>>;
+}
+
+impl<S: StateStore> DynStateStore for S {
+    fn dyn_put<'a>(
+        &'a self, owner: &'a str, key: &'a str, state: &'a RawState,
+    ) -> BoxFuture<'a, Result<(), StateError>> {
+        Box::pin(async move { self.put(owner, key, &deserialize::<Value>(state)?).await })
+    }
+
+    fn dyn_get<'a>(
+        &'a self, owner: &'a str, key: &'a str,
+    ) -> BoxFuture<'a, Result<RawState, StateError>> {
+        Box::pin(async move { captured(self.get(owner, key).await?) })
+    }
+
+    fn dyn_purge<'a>(
+        &'a self, owner: &'a str, key: &'a str,
+    ) -> BoxFuture<'a, Result<(), StateError>> {
+        Box::pin(self.purge(owner, key))
+    }
+
+    fn dyn_take<'a>(
+        &'a self, owner: &'a str, key: &'a str,
+    ) -> BoxFuture<'a, Result<RawState, StateError>> {
+        Box::pin(async move { captured(self.take(owner, key).await?) })
+    }
+
+    fn dyn_put_if_version<'a>(
+        &'a self, owner: &'a str, key: &'a str, state: &'a RawState,
+    ) -> BoxFuture<'a, Result<u64, StateError>> {
+        Box::pin(
+            async move { self.put_if_version(owner, key, &deserialize::<Value>(state)?).await },
+        )
+    }
+
+    fn dyn_list<'a>(
+        &'a self, owner: &'a str, prefix: &'a str, cursor: Option<&'a str>, limit: usize,
+    ) -> BoxFuture<'a, Result<Page, StateError>> {
+        Box::pin(self.list(owner, prefix, cursor, limit))
+    }
+
+    fn dyn_get_many<'a>(
+        &'a self, owner: &'a str, keys: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Vec<Option<RawState>>, StateError>> {
+        Box::pin(async move {
+            let states = self.get_many(owner, keys).await?;
+            states.into_iter().map(|state| state.map(captured).transpose()).collect()
+        })
+    }
+
+    fn dyn_put_many<'a>(
+        &'a self, owner: &'a str, entries: &'a [(&'a str, RawState)],
+    ) -> BoxFuture<'a, Result<(), StateError>> {
+        Box::pin(async move {
+            let entries = entries
+                .iter()
+                .map(|(key, state)| Ok((*key, deserialize::<Value>(state)?)))
+                .collect::<Result<Vec<_>, StateError>>()?;
+            self.put_many(owner, &entries).await
+        })
+    }
+
+    fn dyn_purge_many<'a>(
+        &'a self, owner: &'a str, keys: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<(), StateError>> {
+        Box::pin(self.purge_many(owner, keys))
+    }
+}
+
+// Methods are called on the trait object (`**self`) rather than the `Arc`:
+// the `Arc` itself implements `DynStateStore` through this impl, so calling
+// its methods would recurse.
+impl StateStore for Arc<dyn DynStateStore> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        (**self).dyn_put(owner, key, &serialize(state)?).await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        deserialize(&(**self).dyn_get(owner, key).await?)
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        (**self).dyn_purge(owner, key).await
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        deserialize(&(**self).dyn_take(owner, key).await?)
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        (**self).dyn_put_if_version(owner, key, &serialize(state)?).await
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        (**self).dyn_list(owner, prefix, cursor, limit).await
+    }
+
+    async fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        let states = (**self).dyn_get_many(owner, keys).await?;
+        states.iter().map(|state| state.as_ref().map(deserialize).transpose()).collect()
+    }
+
+    async fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        let entries = entries
+            .iter()
+            .map(|(key, state)| Ok((*key, serialize(state)?)))
+            .collect::<Result<Vec<_>, StateError>>()?;
+        (**self).dyn_put_many(owner, &entries).await
+    }
+
+    async fn purge_many(&self, owner: &str, keys: &[&str]) -> Result<(), StateError> {
+        (**self).dyn_purge_many(owner, keys).await
+    }
+}
+
+// Serialize state as JSON, converting through a JSON value so bodies JSON
+// cannot represent, such as integers wider than 64 bits, are rejected.
+fn serialize<T: Serialize>(state: &State<T>) -> Result<RawState, StateError> {
+    let value = serde_json::to_value(&state.body).map_err(not_json)?;
+    Ok(State {
+        body: serde_json::to_vec(&value).map_err(anyhow::Error::from)?,
+        expires_at: state.expires_at,
+        version: state.version,
+    })
+}
+
+// JSON for state read from a store, captured as the store decodes the body.
+fn captured(state: State<Captured<IgnoredAny>>) -> Result<RawState, StateError> {
+    let Some(body) = state.body.json else {
+        return Err(not_json("body contains values without a JSON representation"));
+    };
+    Ok(State {
+        body,
+        expires_at: state.expires_at,
+        version: state.version,
+    })
+}
+
+fn not_json(e: impl std::fmt::Display) -> StateError {
+    StateError::Internal(anyhow!("state body cannot be represented as JSON: {e}"))
+}
+
+fn deserialize<T: DeserializeOwned>(state: &RawState) -> Result<State<T>, StateError> {
+    Ok(State {
+        body: serde_json::from_slice(&state.body).map_err(anyhow::Error::from)?,
+        expires_at: state.expires_at,
+        version: state.version,
+    })
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let inner = InMemoryStore::new();
+        let store: Arc<dyn DynStateStore> = Arc::new(inner.clone());
+        let state = State::with_ttl(vec!["alice".to_string()], TimeDelta::minutes(5));
+
+        store.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(store.get::<Vec<String>>("owner", "key").await.expect("should get"), state);
+        assert_eq!(inner.get::<Vec<String>>("owner", "key").await.expect("should get"), state);
+
+        let bytes = store.dyn_get("owner", "key").await.expect("should get bytes");
+        assert_eq!(bytes.body, br#"["alice"]"#);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn codec() {
+        use std::collections::BTreeMap;
+
+        use crate::state::Cbor;
+
+        let inner = InMemoryStore::new().with_codec(Cbor);
+        let store: Arc<dyn DynStateStore> = Arc::new(inner.clone());
+
+        let state = State::with_ttl(BTreeMap::from([(1_u8, u64::MAX)]), TimeDelta::minutes(5));
+        inner.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(
+            store.get::<BTreeMap<u8, u64>>("owner", "key").await.expect("should get"),
+            state
+        );
+
+        let wide = State::with_ttl(u128::MAX, TimeDelta::minutes(5));
+        let err = store.put("owner", "wide", &wide).await.expect_err("should reject u128");
+        assert!(matches!(err, StateError::Internal(_)));
+
+        let nan = State::with_ttl(f64::NAN, TimeDelta::minutes(5));
+        inner.put("owner", "nan", &nan).await.expect("should put");
+        let err = store.get::<f64>("owner", "nan").await.expect_err("should reject NaN");
+        assert!(matches!(err, StateError::Internal(_)));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn conformance() {
+        let store: Arc<dyn DynStateStore> = Arc::new(InMemoryStore::new());
+        crate::state::testing::conformance(store).await;
+    }
+}
@@ -0,0 +1,391 @@
+//! # Encrypted Store
+//!
+//! A [`StateStore`] decorator that encrypts state bodies before handing them
+//! to an inner store.
+//!
+//! Bodies are serialized using the store's [`Codec`], JSON by default, and
+//! sealed with AES-256-GCM using a key scoped to the owner. The owner and key
+//! the state is stored under are bound to the ciphertext as associated data,
+//! so sealed bodies cannot be moved between entries. Expiry and version are
+//! left in the clear so the inner store can continue to manage them.
+//!
+//! Ciphertext does not compress, so `Compressed` should be used as the
+//! encrypted store's codec rather than the inner store's.
+//!
+//! Each sealed body records the id of the key used to seal it, allowing keys
+//! to be rotated without re-encrypting existing state.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::future::{self, Future};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use futures_core::Stream;
+use hkdf::Hkdf;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::state::{ChangeEvent, Codec, Json, Page, State, StateError, StateStore, WatchStore};
+
+/// An encryption key along with the id used to look it up.
+#[derive(Clone)]
+pub struct Key {
+    /// The key id, stored alongside sealed bodies.
+    pub id: String,
+
+    /// The 256-bit AES key.
+    pub secret: [u8; 32],
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+/// The `Keyring` trait is implemented to provide owner-scoped keys to an
+/// [`EncryptedStore`].
+pub trait Keyring: Send + Sync {
+    /// The key to use when sealing state for the owner.
+    fn current(&self, owner: &str) -> impl Future<Output = Result<Key>> + Send;
+
+    /// The key with the provided id, used to open state sealed for the owner.
+    fn key(&self, owner: &str, id: &str) -> impl Future<Output = Result<Key>> + Send;
+}
+
+/// A [`Keyring`] that derives owner-scoped keys from one or more master keys
+/// using HKDF-SHA256.
+///
+/// New state is sealed using the current master key. Older master keys are
+/// retained so state sealed before a rotation can still be opened.
+#[derive(Clone)]
+pub struct DerivedKeyring {
+    current: String,
+    masters: HashMap<String, [u8; 32]>,
+}
+
+impl Debug for DerivedKeyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DerivedKeyring").field("current", &self.current).finish_non_exhaustive()
+    }
+}
+
+impl DerivedKeyring {
+    /// Create a keyring using the provided master key as the current key.
+    #[must_use]
+    pub fn new(id: impl Into<String>, master: [u8; 32]) -> Self {
+        let id = id.into();
+        Self {
+            masters: HashMap::from([(id.clone(), master)]),
+            current: id,
+        }
+    }
+
+    /// Add a master key and make it the current key. Previous keys are kept
+    /// for opening existing state.
+    #[must_use]
+    pub fn rotate(mut self, id: impl Into<String>, master: [u8; 32]) -> Self {
+        let id = id.into();
+        self.masters.insert(id.clone(), master);
+        self.current = id;
+        self
+    }
+
+    fn derive(&self, owner: &str, id: &str) -> Result<Key> {
+        let Some(master) = self.masters.get(id) else {
+            bail!("unknown key id: {id}");
+        };
+        let mut secret = [0u8; 32];
+        Hkdf::<Sha256>::new(None, master)
+            .expand_multi_info(&[b"credibil-state:", owner.as_bytes()], &mut secret)
+            .map_err(|e| anyhow!("failed to derive key: {e}"))?;
+
+        Ok(Key {
+            id: id.to_string(),
+            secret,
+        })
+    }
+}
+
+impl Keyring for DerivedKeyring {
+    fn current(&self, owner: &str) -> impl Future<Output = Result<Key>> + Send {
+        future::ready(self.derive(owner, &self.current))
+    }
+
+    fn key(&self, owner: &str, id: &str) -> impl Future<Output = Result<Key>> + Send {
+        future::ready(self.derive(owner, id))
+    }
+}
+
+/// A [`StateStore`] that encrypts state bodies before delegating to an inner
+/// store.
+#[derive(Clone, Debug)]
+pub struct EncryptedStore<S, K = DerivedKeyring, C = Json> {
+    inner: S,
+    keyring: K,
+    codec: C,
+}
+
+/// A sealed state body as stored by the inner store.
+#[derive(Debug, Deserialize, Serialize)]
+struct Sealed {
+    kid: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl<S: StateStore, K: Keyring> EncryptedStore<S, K> {
+    /// Wrap `inner`, encrypting state using keys from `keyring`.
+    pub const fn new(inner: S, keyring: K) -> Self {
+        Self {
+            inner,
+            keyring,
+            codec: Json,
+        }
+    }
+}
+
+impl<S: StateStore, K: Keyring, C: Codec> EncryptedStore<S, K, C> {
+    /// Serialize state bodies using the provided codec before sealing them.
+    ///
+    /// Existing entries are not re-encoded, so a store should always be
+    /// opened with the codec its entries were written with.
+    #[must_use]
+    pub fn with_codec<D: Codec>(self, codec: D) -> EncryptedStore<S, K, D> {
+        EncryptedStore {
+            inner: self.inner,
+            keyring: self.keyring,
+            codec,
+        }
+    }
+
+    /// Consume the wrapper, returning the inner store.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    async fn seal<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<State<Sealed>> {
+        let data_key = self.keyring.current(owner).await?;
+        let cipher = Aes256Gcm::new(&data_key.secret.into());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let payload = Payload {
+            msg: &self.codec.encode(&state.body)?,
+            aad: &aad(owner, key),
+        };
+        let ciphertext =
+            cipher.encrypt(&nonce, payload).map_err(|e| anyhow!("failed to encrypt: {e}"))?;
+
+        Ok(State {
+            body: Sealed {
+                kid: data_key.id,
+                nonce: URL_SAFE_NO_PAD.encode(nonce),
+                ciphertext: URL_SAFE_NO_PAD.encode(ciphertext),
+            },
+            expires_at: state.expires_at,
+            version: state.version,
+        })
+    }
+
+    async fn open<T: DeserializeOwned>(
+        &self, owner: &str, key: &str, state: State<Sealed>,
+    ) -> Result<State<T>> {
+        let data_key = self.keyring.key(owner, &state.body.kid).await?;
+        let cipher = Aes256Gcm::new(&data_key.secret.into());
+
+        let nonce = URL_SAFE_NO_PAD.decode(&state.body.nonce)?;
+        if nonce.len() != 12 {
+            bail!("invalid nonce length for key: {key}");
+        }
+        let ciphertext = URL_SAFE_NO_PAD.decode(&state.body.ciphertext)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &aad(owner, key),
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|e| anyhow!("failed to decrypt state for key {key}: {e}"))?;
+
+        Ok(State {
+            body: self.codec.decode(&plaintext)?,
+            expires_at: state.expires_at,
+            version: state.version,
+        })
+    }
+}
+
+impl<S: StateStore, K: Keyring, C: Codec> StateStore for EncryptedStore<S, K, C> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        let sealed = self.seal(owner, key, state).await?;
+        self.inner.put(owner, key, &sealed).await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let sealed = self.inner.get::<Sealed>(owner, key).await?;
+        Ok(self.open(owner, key, sealed).await?)
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        self.inner.purge(owner, key).await
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let sealed = self.inner.take::<Sealed>(owner, key).await?;
+        Ok(self.open(owner, key, sealed).await?)
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        let sealed = self.seal(owner, key, state).await?;
+        self.inner.put_if_version(owner, key, &sealed).await
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        self.inner.list(owner, prefix, cursor, limit).await
+    }
+
+    async fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        let sealed = self.inner.get_many::<Sealed>(owner, keys).await?;
+        let mut states = Vec::with_capacity(sealed.len());
+        for (key, sealed) in keys.iter().zip(sealed) {
+            let state = match sealed {
+                Some(sealed) => Some(self.open(owner, key, sealed).await?),
+                None => None,
+            };
+            states.push(state);
+        }
+        Ok(states)
+    }
+
+    async fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        let mut sealed = Vec::with_capacity(entries.len());
+        for (key, state) in entries {
+            sealed.push((*key, self.seal(owner, key, state).await?));
+        }
+        self.inner.put_many(owner, &sealed).await
+    }
+
+    async fn purge_many(&self, owner: &str, keys: &[&str]) -> Result<(), StateError> {
+        self.inner.purge_many(owner, keys).await
+    }
+}
+
+impl<S: WatchStore, K: Keyring, C: Codec> WatchStore for EncryptedStore<S, K, C> {
+    fn watch(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<impl Stream<Item = ChangeEvent> + Send + 'static, StateError>> + Send
+    {
+        self.inner.watch(owner, key)
+    }
+}
+
+// Associated data binding a sealed body to the entry it is stored under.
+fn aad(owner: &str, key: &str) -> Vec<u8> {
+    [owner.as_bytes(), &[0], key.as_bytes()].concat()
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let inner = InMemoryStore::new();
+        let store = EncryptedStore::new(inner.clone(), DerivedKeyring::new("k1", [1; 32]));
+        let state = State::with_ttl("secret".to_string(), TimeDelta::minutes(5));
+
+        store.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get"), state);
+
+        let sealed = inner.get::<Sealed>("owner", "key").await.expect("should get sealed");
+        assert_eq!(sealed.body.kid, "k1");
+        assert!(!sealed.body.ciphertext.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let inner = InMemoryStore::new();
+        let state = State::with_ttl("secret".to_string(), TimeDelta::minutes(5));
+
+        let store = EncryptedStore::new(inner.clone(), DerivedKeyring::new("k1", [1; 32]));
+        store.put("owner", "old", &state).await.expect("should put");
+
+        let keyring = DerivedKeyring::new("k1", [1; 32]).rotate("k2", [2; 32]);
+        let store = EncryptedStore::new(inner.clone(), keyring);
+        store.put("owner", "new", &state).await.expect("should put");
+
+        assert_eq!(store.get::<String>("owner", "old").await.expect("should get"), state);
+        assert_eq!(store.get::<String>("owner", "new").await.expect("should get"), state);
+        assert_eq!(inner.get::<Sealed>("owner", "new").await.expect("should get").body.kid, "k2");
+    }
+
+    #[tokio::test]
+    async fn bound_to_entry() {
+        let inner = InMemoryStore::new();
+        let store = EncryptedStore::new(inner.clone(), DerivedKeyring::new("k1", [1; 32]));
+        let state = State::with_ttl("secret".to_string(), TimeDelta::minutes(5));
+        store.put("alice", "key", &state).await.expect("should put");
+
+        // copy the sealed body to another owner
+        let sealed = inner.get::<Sealed>("alice", "key").await.expect("should get");
+        inner.put("bob", "key", &sealed).await.expect("should put");
+        store.get::<String>("bob", "key").await.expect_err("should not decrypt");
+    }
+
+    #[cfg(all(feature = "cbor", feature = "compression"))]
+    #[tokio::test]
+    async fn codec() {
+        use std::collections::BTreeMap;
+
+        use crate::state::{Cbor, Compressed};
+
+        let inner = InMemoryStore::new();
+        let store = EncryptedStore::new(inner.clone(), DerivedKeyring::new("k1", [1; 32]))
+            .with_codec(Compressed::new(Cbor));
+        let state =
+            State::with_ttl(BTreeMap::from([(1_u8, "a".repeat(1000))]), TimeDelta::minutes(5));
+
+        store.put("owner", "key", &state).await.expect("should put");
+        assert_eq!(
+            store.get::<BTreeMap<u8, String>>("owner", "key").await.expect("should get"),
+            state
+        );
+
+        // the body is compressed before it is sealed
+        let sealed = inner.get::<Sealed>("owner", "key").await.expect("should get sealed");
+        assert!(sealed.body.ciphertext.len() < 200);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn conformance() {
+        crate::state::testing::conformance(EncryptedStore::new(
+            InMemoryStore::new(),
+            DerivedKeyring::new("k1", [1; 32]),
+        ))
+        .await;
+    }
+}
@@ -0,0 +1,172 @@
+//! # State Error
+//!
+//! Errors returned by [`StateStore`](crate::state::StateStore) operations.
+
+use std::io;
+
+use http::StatusCode;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use thiserror::Error;
+
+/// Errors returned by [`StateStore`](crate::state::StateStore) operations.
+///
+/// Variants distinguish failures callers are expected to handle, such as
+/// missing or expired state, from failures of the underlying store.
+#[derive(Debug, Error)]
+pub enum StateError {
+    /// No state is stored for the key.
+    #[error("no state found for key: {0}")]
+    NotFound(String),
+
+    /// State is stored for the key but has expired.
+    #[error("state has expired for key: {0}")]
+    Expired(String),
+
+    /// A versioned write was rejected because the stored version did not
+    /// match the expected version.
+    #[error("version conflict for key: {key}: expected {expected}, found {found}")]
+    Conflict {
+        /// The key written to.
+        key: String,
+
+        /// The version the write expected to replace.
+        expected: u64,
+
+        /// The version currently stored.
+        found: u64,
+    },
+
+    /// The lock for the key is held by another caller.
+    #[error("lock held for key: {0}")]
+    Locked(String),
+
+    /// A lock lease is no longer held, having expired or been superseded.
+    #[error("lease lost for key: {0}")]
+    LeaseLost(String),
+
+    /// The write would exceed the owner's quota.
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// The operation is not permitted for the owner.
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    /// The store does not support the operation.
+    #[error("operation not supported by the store: {0}")]
+    Unsupported(&'static str),
+
+    /// The underlying store could not be reached or failed to complete the
+    /// operation.
+    #[error("state store unavailable: {0}")]
+    Unavailable(#[source] anyhow::Error),
+
+    /// Any other failure, such as state that cannot be serialized.
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl StateError {
+    /// The HTTP status code best describing the error.
+    #[must_use]
+    pub const fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Expired(_) => StatusCode::GONE,
+            Self::Conflict { .. } | Self::LeaseLost(_) => StatusCode::CONFLICT,
+            Self::Locked(_) => StatusCode::LOCKED,
+            Self::QuotaExceeded(_) | Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A short, stable code identifying the kind of error.
+    #[must_use]
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::Expired(_) => "expired",
+            Self::Conflict { .. } => "conflict",
+            Self::Locked(_) => "locked",
+            Self::LeaseLost(_) => "lease_lost",
+            Self::QuotaExceeded(_) => "quota_exceeded",
+            Self::Forbidden(_) => "forbidden",
+            Self::Unsupported(_) => "unsupported",
+            Self::Unavailable(_) => "unavailable",
+            Self::Internal(_) => "server_error",
+        }
+    }
+}
+
+/// Serializes the error as an API error response body:
+/// `{"error": "<code>", "error_description": "<message>"}`.
+///
+/// Details of store failures are omitted from the description so they are
+/// not leaked to API clients.
+impl Serialize for StateError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let description = match self {
+            Self::Unavailable(_) => "state store unavailable".to_string(),
+            Self::Internal(_) => "internal server error".to_string(),
+            _ => self.to_string(),
+        };
+
+        let mut state = serializer.serialize_struct("StateError", 2)?;
+        state.serialize_field("error", self.code())?;
+        state.serialize_field("error_description", &description)?;
+        state.end()
+    }
+}
+
+impl From<StateError> for StatusCode {
+    fn from(error: StateError) -> Self {
+        error.status()
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        Self::Unavailable(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn status() {
+        assert_eq!(StateError::NotFound("key".to_string()).status(), StatusCode::NOT_FOUND);
+        assert_eq!(StateError::Expired("key".to_string()).status(), StatusCode::GONE);
+
+        assert_eq!(StateError::Unsupported("list").status(), StatusCode::NOT_IMPLEMENTED);
+
+        let unavailable = StateError::Unavailable(anyhow!("connection refused"));
+        assert_eq!(StatusCode::from(unavailable), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn serialize() {
+        let error = StateError::Conflict {
+            key: "key".to_string(),
+            expected: 1,
+            found: 2,
+        };
+        assert_eq!(
+            serde_json::to_value(&error).expect("should serialize"),
+            json!({
+                "error": "conflict",
+                "error_description": "version conflict for key: key: expected 1, found 2"
+            })
+        );
+
+        let error = StateError::Internal(anyhow!("secret detail"));
+        let value = serde_json::to_value(&error).expect("should serialize");
+        assert_eq!(value["error_description"], "internal server error");
+    }
+}
@@ -0,0 +1,426 @@
+//! # File Store
+//!
+//! A [`StateStore`] that persists state to the local file system.
+//!
+//! Each entry is written to `<root>/<owner>/<key>.state`, with owner and key
+//! percent-encoded so they are always safe to use as file names. Names too
+//! long once encoded are replaced by their SHA-256 hash. Writes go to
+//! a temporary file that is then renamed over the target, so readers never
+//! observe a partially written entry. Entries are serialized using the
+//! store's [`Codec`](crate::state::Codec), JSON by default.
+//!
+//! Each owner directory also holds an expiry index (`.expiry`) mapping keys
+//! to their expiry time, allowing expired entries to be purged without
+//! reading every entry.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::ErrorKind;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_encode};
+use serde::Serialize;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::clock::{Clock, SystemClock};
+use crate::state::{Codec, Json, KeyInfo, Page, State, StateError, StateStore};
+
+// Characters left unencoded in file names. Notably, '.' is always encoded so
+// encoded names can never be `.`, `..`, or clash with the expiry index.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_');
+
+// Encoded names longer than this are hashed, keeping file names, including
+// the temporary file written alongside each entry, within file system limits.
+const MAX_NAME_LEN: usize = 200;
+
+const INDEX_FILE: &str = ".expiry";
+const EXTENSION: &str = "state";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+type Index = BTreeMap<String, DateTime<Utc>>;
+
+/// A file system backed [`StateStore`].
+///
+/// Cloning the store is cheap and clones share the same index lock. Stores
+/// created separately over the same directory should not be used
+/// concurrently.
+#[derive(Clone, Debug)]
+pub struct FileStore<C = Json> {
+    root: PathBuf,
+    index_lock: Arc<Mutex<()>>,
+    clock: Arc<dyn Clock>,
+    codec: C,
+}
+
+impl FileStore {
+    /// Create a store rooted at the provided directory. The directory is
+    /// created on first write if it does not already exist.
+    #[must_use]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index_lock: Arc::new(Mutex::new(())),
+            clock: Arc::new(SystemClock),
+            codec: Json,
+        }
+    }
+}
+
+impl<C: Codec> FileStore<C> {
+    /// Use the provided clock to determine when entries have expired.
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Serialize state using the provided codec.
+    ///
+    /// Existing entries are not re-encoded, so a store should always be
+    /// opened with the codec its entries were written with.
+    #[must_use]
+    pub fn with_codec<D: Codec>(self, codec: D) -> FileStore<D> {
+        FileStore {
+            root: self.root,
+            index_lock: self.index_lock,
+            clock: self.clock,
+            codec,
+        }
+    }
+
+    /// Remove all expired entries, returning the number removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store directory cannot be read or an expired
+    /// entry cannot be removed.
+    pub async fn purge_expired(&self) -> Result<usize, StateError> {
+        let _guard = self.index_lock.lock().await;
+        let now = self.clock.now();
+        let mut removed = 0;
+
+        let mut owners = match fs::read_dir(&self.root).await {
+            Ok(owners) => owners,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(owner) = owners.next_entry().await? {
+            if !owner.file_type().await?.is_dir() {
+                continue;
+            }
+            let dir = owner.path();
+            let mut index = read_index(&dir).await?;
+            let expired = index
+                .iter()
+                .filter(|(_, expires_at)| **expires_at < now)
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            if expired.is_empty() {
+                continue;
+            }
+
+            for key in &expired {
+                remove_file(&dir.join(file_name(key))).await?;
+                index.remove(key);
+            }
+            write_index(&dir, &index).await?;
+            removed += expired.len();
+        }
+
+        Ok(removed)
+    }
+
+    fn owner_dir(&self, owner: &str) -> PathBuf {
+        self.root.join(encode(owner))
+    }
+}
+
+impl<C: Codec> StateStore for FileStore<C> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        let data = self.codec.encode(state)?;
+        let dir = self.owner_dir(owner);
+        fs::create_dir_all(&dir).await?;
+
+        let _guard = self.index_lock.lock().await;
+        write_atomic(&dir.join(file_name(key)), &data).await?;
+
+        let mut index = read_index(&dir).await?;
+        index.insert(key.to_string(), state.expires_at);
+        write_index(&dir, &index).await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let path = self.owner_dir(owner).join(file_name(key));
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(StateError::NotFound(key.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let state: State<T> = self.codec.decode(&data)?;
+        if state.expires_at < self.clock.now() {
+            return Err(StateError::Expired(key.to_string()));
+        }
+        Ok(state)
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        let dir = self.owner_dir(owner);
+
+        let _guard = self.index_lock.lock().await;
+        remove_file(&dir.join(file_name(key))).await?;
+
+        let mut index = read_index(&dir).await?;
+        if index.remove(key).is_some() {
+            write_index(&dir, &index).await?;
+        }
+        Ok(())
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let dir = self.owner_dir(owner);
+        let path = dir.join(file_name(key));
+
+        let _guard = self.index_lock.lock().await;
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(StateError::NotFound(key.to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        remove_file(&path).await?;
+
+        let mut index = read_index(&dir).await?;
+        if index.remove(key).is_some() {
+            write_index(&dir, &index).await?;
+        }
+
+        let state: State<T> = self.codec.decode(&data)?;
+        if state.expires_at < self.clock.now() {
+            return Err(StateError::Expired(key.to_string()));
+        }
+        Ok(state)
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        let start = match cursor {
+            Some(cursor) if cursor >= prefix => Bound::Excluded(cursor),
+            _ => Bound::Included(prefix),
+        };
+
+        let index = read_index(&self.owner_dir(owner)).await?;
+        let keys = index
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, expires_at)| KeyInfo {
+                key: key.clone(),
+                expires_at: *expires_at,
+            });
+
+        Ok(Page::from_sorted(keys, limit))
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        let version = state.version + 1;
+        let data = self.codec.encode(&state.with_version(version))?;
+        let dir = self.owner_dir(owner);
+        fs::create_dir_all(&dir).await?;
+
+        // holding the index lock serializes writers for the comparison
+        let _guard = self.index_lock.lock().await;
+        let path = dir.join(file_name(key));
+
+        let current = match fs::read(&path).await {
+            Ok(data) => {
+                let stored: State<IgnoredAny> = self.codec.decode(&data)?;
+                if stored.expires_at < self.clock.now() { 0 } else { stored.version }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if current != state.version {
+            return Err(StateError::Conflict {
+                key: key.to_string(),
+                expected: state.version,
+                found: current,
+            });
+        }
+        write_atomic(&path, &data).await?;
+
+        let mut index = read_index(&dir).await?;
+        index.insert(key.to_string(), state.expires_at);
+        write_index(&dir, &index).await?;
+
+        Ok(version)
+    }
+}
+
+fn encode(name: &str) -> String {
+    let encoded = percent_encode(name.as_bytes(), UNRESERVED).to_string();
+    if encoded.len() <= MAX_NAME_LEN {
+        return encoded;
+    }
+    // '~' is always percent-encoded, so hashed names cannot clash with
+    // encoded ones
+    let digest = Sha256::digest(name.as_bytes());
+    digest.iter().fold(String::from("~"), |mut name, b| {
+        // writing to a `String` cannot fail
+        let _ = write!(name, "{b:02x}");
+        name
+    })
+}
+
+fn file_name(key: &str) -> String {
+    format!("{}.{EXTENSION}", encode(key))
+}
+
+async fn read_index(dir: &Path) -> Result<Index, StateError> {
+    match fs::read(dir.join(INDEX_FILE)).await {
+        Ok(data) => Ok(serde_json::from_slice(&data).map_err(anyhow::Error::from)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Index::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn write_index(dir: &Path, index: &Index) -> Result<(), StateError> {
+    let path = dir.join(INDEX_FILE);
+    if index.is_empty() {
+        return remove_file(&path).await;
+    }
+    write_atomic(&path, &serde_json::to_vec(index).map_err(anyhow::Error::from)?).await
+}
+
+// Write to a temporary file in the target's directory then rename it over the
+// target. Temporary names start with '.' so they never clash with entries.
+async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), StateError> {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Err(anyhow!("invalid file path: {}", path.display()).into());
+    };
+    let count = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temp = path.with_file_name(format!(".{name}.{}.{count}.tmp", process::id()));
+
+    let mut file = fs::File::create(&temp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if let Err(e) = fs::rename(&temp, path).await {
+        remove_file(&temp).await?;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+async fn remove_file(path: &Path) -> Result<(), StateError> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn path_encoding() {
+        let dir = tempfile::tempdir().expect("should create temp dir");
+        let store = FileStore::new(dir.path());
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        store.put("owner", "../key", &alice).await.expect("should put");
+        assert!(dir.path().join("owner").join("%2E%2E%2Fkey.state").exists());
+
+        store.purge("owner", "../key").await.expect("should purge");
+        assert!(!dir.path().join("owner").join(INDEX_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn long_key() {
+        let dir = tempfile::tempdir().expect("should create temp dir");
+        let store = FileStore::new(dir.path());
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+        let key = format!("did:web:issuer.example.com:{}", "a".repeat(150));
+
+        store.put("owner", &key, &alice).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", &key).await.expect("should get"), alice);
+
+        let keys = store.scan("owner", "did:").await.expect("should scan");
+        assert_eq!(keys.first().map(|info| info.key.as_str()), Some(key.as_str()));
+    }
+
+    #[tokio::test]
+    async fn purge_expired() {
+        let dir = tempfile::tempdir().expect("should create temp dir");
+        let store = FileStore::new(dir.path());
+
+        let live = State::with_ttl("live".to_string(), TimeDelta::minutes(5));
+        store.put("owner", "live", &live).await.expect("should put");
+        store
+            .put("owner", "dead", &State::with_ttl("dead".to_string(), TimeDelta::seconds(-1)))
+            .await
+            .expect("should put");
+        store
+            .put("other", "dead", &State::with_ttl("dead".to_string(), TimeDelta::seconds(-1)))
+            .await
+            .expect("should put");
+
+        let err = store.get::<String>("owner", "dead").await.expect_err("should be expired");
+        assert!(matches!(err, StateError::Expired(_)));
+
+        assert_eq!(store.purge_expired().await.expect("should purge"), 2);
+        assert!(!dir.path().join("owner").join("dead.state").exists());
+        assert_eq!(store.get::<String>("owner", "live").await.expect("should get"), live);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn codec() {
+        use crate::state::Cbor;
+
+        let dir = tempfile::tempdir().expect("should create temp dir");
+        let store = FileStore::new(dir.path()).with_codec(Cbor);
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        store.put("owner", "key", &alice).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get"), alice);
+
+        let data = fs::read(dir.path().join("owner").join("key.state")).await.expect("should read");
+        assert_eq!(Cbor.decode::<State<String>>(&data).expect("should decode"), alice);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn conformance() {
+        let dir = tempfile::tempdir().expect("should create temp dir");
+        crate::state::testing::conformance(FileStore::new(dir.path())).await;
+    }
+}
@@ -0,0 +1,60 @@
+//! # Lock
+//!
+//! Distributed locks, used to serialize processing of the same key across
+//! replicas, such as redemption of a credential offer.
+//!
+//! Locks are leased for a fixed time and must be renewed by long-running
+//! holders. Each lease carries a fencing token that increases with every
+//! acquisition, allowing downstream writes made by a holder whose lease has
+//! lapsed to be detected and rejected.
+
+use std::future::Future;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::state::StateError;
+
+/// A lease on a lock held for a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    /// The fencing token for the lease. Tokens increase with each
+    /// acquisition of the lock, so a larger token identifies a later holder.
+    pub token: u64,
+
+    /// Time the lease expires unless renewed.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The `LockStore` trait is implemented by stores able to provide
+/// exclusive, time-limited locks on keys.
+///
+/// Locks share the owner and key model of
+/// [`StateStore`](crate::state::StateStore) but are held separately from
+/// stored state, so a key may be locked whether or not state is stored for
+/// it.
+pub trait LockStore: Send + Sync {
+    /// Acquire the lock for a key, leasing it for `ttl`.
+    ///
+    /// Returns [`StateError::Locked`] if an unexpired lease is held by
+    /// another caller, or an error if `ttl` is not positive or takes the
+    /// expiry out of range.
+    fn acquire(
+        &self, owner: &str, key: &str, ttl: TimeDelta,
+    ) -> impl Future<Output = Result<Lease, StateError>> + Send;
+
+    /// Extend a lease so it expires `ttl` from now, returning the renewed
+    /// lease. The fencing token is unchanged.
+    ///
+    /// Returns [`StateError::LeaseLost`] if the lease has expired, in which
+    /// case the lock may since have been acquired by another caller.
+    fn renew(
+        &self, owner: &str, key: &str, lease: &Lease, ttl: TimeDelta,
+    ) -> impl Future<Output = Result<Lease, StateError>> + Send;
+
+    /// Release a lease, allowing the lock to be acquired by another caller.
+    ///
+    /// Releasing a lease that has expired or been superseded does nothing.
+    fn release(
+        &self, owner: &str, key: &str, lease: &Lease,
+    ) -> impl Future<Output = Result<(), StateError>> + Send;
+}
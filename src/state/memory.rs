@@ -0,0 +1,719 @@
+//! # In-Memory Store
+//!
+//! A [`StateStore`] that keeps state in process memory. It is intended for
+//! unit tests and single-node deployments where state does not need to
+//! survive a restart.
+//!
+//! The store also implements [`WatchStore`], broadcasting changes to
+//! watchers in the same process, and [`LockStore`], with locks held in the
+//! same process.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::{self, Future};
+use std::ops::Bound;
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_core::Stream;
+use futures_util::stream;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Sender};
+
+use crate::clock::{Clock, SystemClock};
+use crate::state::{
+    ChangeEvent, Codec, Json, KeyInfo, Lease, LockStore, Page, State, StateError, StateStore,
+    WatchStore,
+};
+
+// Number of changes buffered for each watcher before older changes are
+// dropped. Changes to every key share the buffer, so a watcher that falls
+// behind is sent `ChangeEvent::Lagged`.
+const CHANGE_CAPACITY: usize = 64;
+
+type Owners = HashMap<String, BTreeMap<String, Entry>>;
+
+/// An in-memory [`StateStore`].
+///
+/// Cloning the store is cheap and clones share the same underlying state.
+/// State is serialized using the store's [`Codec`], JSON by default.
+#[derive(Clone, Debug)]
+pub struct InMemoryStore<C = Json> {
+    owners: Arc<RwLock<Owners>>,
+    clock: Arc<dyn Clock>,
+    codec: C,
+    changes: Sender<Change>,
+    locks: Arc<Mutex<Locks>>,
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self {
+            owners: Arc::default(),
+            clock: Arc::new(SystemClock),
+            codec: Json,
+            changes: broadcast::channel(CHANGE_CAPACITY).0,
+            locks: Arc::default(),
+        }
+    }
+}
+
+/// A change broadcast to watchers.
+#[derive(Clone, Debug)]
+struct Change {
+    owner: String,
+    key: String,
+    event: ChangeEvent,
+}
+
+/// Leases held on keys, along with the last fencing token issued.
+#[derive(Debug, Default)]
+struct Locks {
+    token: u64,
+    leases: HashMap<(String, String), Lease>,
+}
+
+/// A serialized `State<T>` along with its expiry.
+#[derive(Clone, Debug)]
+struct Entry {
+    data: Vec<u8>,
+    expires_at: DateTime<Utc>,
+    version: u64,
+}
+
+impl Locks {
+    // Drop leases that have expired.
+    fn prune(&mut self, now: DateTime<Utc>) {
+        self.leases.retain(|_, lease| lease.expires_at >= now);
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at < now
+    }
+}
+
+impl InMemoryStore {
+    /// Create a new, empty store.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: Codec> InMemoryStore<C> {
+    /// Use the provided clock to determine when entries have expired.
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Serialize state using the provided codec.
+    ///
+    /// Entries already held by the store are not re-encoded, so the codec
+    /// should be set before the store is first used.
+    #[must_use]
+    pub fn with_codec<D: Codec>(self, codec: D) -> InMemoryStore<D> {
+        InMemoryStore {
+            owners: self.owners,
+            clock: self.clock,
+            codec,
+            changes: self.changes,
+            locks: self.locks,
+        }
+    }
+
+    /// Start a background task that removes expired entries and leases every
+    /// `interval`.
+    ///
+    /// The task stops once the last clone of the store has been dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from outside the context of a Tokio runtime.
+    #[must_use]
+    pub fn with_sweeper(self, interval: Duration) -> Self {
+        let owners = Arc::downgrade(&self.owners);
+        let locks = Arc::downgrade(&self.locks);
+        tokio::spawn(sweep_every(owners, locks, Arc::clone(&self.clock), interval));
+        self
+    }
+
+    /// Remove all expired entries and leases, returning the number of
+    /// entries removed.
+    #[must_use]
+    pub fn sweep(&self) -> usize {
+        let now = self.clock.now();
+        self.locks().prune(now);
+        sweep(&mut self.write(), now)
+    }
+
+    // Returns a copy of the serialized state for an unexpired entry.
+    fn data(&self, owner: &str, key: &str) -> Result<Vec<u8>, StateError> {
+        match self.read().get(owner).and_then(|entries| entries.get(key)) {
+            None => Err(StateError::NotFound(key.to_string())),
+            Some(entry) if entry.is_expired(self.clock.now()) => {
+                Err(StateError::Expired(key.to_string()))
+            }
+            Some(entry) => Ok(entry.data.clone()),
+        }
+    }
+
+    // Removes the entry, returning its serialized state if unexpired.
+    fn remove(&self, owner: &str, key: &str) -> Result<Vec<u8>, StateError> {
+        let mut owners = self.write();
+        let entry = owners.get_mut(owner).and_then(|entries| entries.remove(key));
+        if owners.get(owner).is_some_and(BTreeMap::is_empty) {
+            owners.remove(owner);
+        }
+        drop(owners);
+
+        if entry.is_some() {
+            self.notify(owner, key, ChangeEvent::Removed);
+        }
+        match entry {
+            None => Err(StateError::NotFound(key.to_string())),
+            Some(entry) if entry.is_expired(self.clock.now()) => {
+                Err(StateError::Expired(key.to_string()))
+            }
+            Some(entry) => Ok(entry.data),
+        }
+    }
+
+    fn put_versioned<T: Serialize>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        let version = state.version + 1;
+        let entry = Entry {
+            data: self.codec.encode(&state.with_version(version))?,
+            expires_at: state.expires_at,
+            version,
+        };
+
+        let mut owners = self.write();
+        let current = owners
+            .get(owner)
+            .and_then(|entries| entries.get(key))
+            .filter(|entry| !entry.is_expired(self.clock.now()))
+            .map_or(0, |entry| entry.version);
+        if current != state.version {
+            return Err(StateError::Conflict {
+                key: key.to_string(),
+                expected: state.version,
+                found: current,
+            });
+        }
+        owners.entry(owner.to_string()).or_default().insert(key.to_string(), entry);
+        drop(owners);
+
+        self.notify(
+            owner,
+            key,
+            ChangeEvent::Put {
+                version,
+                expires_at: state.expires_at,
+            },
+        );
+        Ok(version)
+    }
+
+    fn acquire_lease(&self, owner: &str, key: &str, ttl: TimeDelta) -> Result<Lease, StateError> {
+        let now = self.clock.now();
+        let expires_at = lease_expiry(now, ttl)?;
+        let id = (owner.to_string(), key.to_string());
+
+        let mut locks = self.locks();
+        if locks.leases.get(&id).is_some_and(|held| held.expires_at >= now) {
+            return Err(StateError::Locked(key.to_string()));
+        }
+        locks.token += 1;
+        let lease = Lease {
+            token: locks.token,
+            expires_at,
+        };
+        locks.leases.insert(id, lease.clone());
+        drop(locks);
+
+        Ok(lease)
+    }
+
+    fn renew_lease(
+        &self, owner: &str, key: &str, lease: &Lease, ttl: TimeDelta,
+    ) -> Result<Lease, StateError> {
+        let now = self.clock.now();
+        let expires_at = lease_expiry(now, ttl)?;
+        let id = (owner.to_string(), key.to_string());
+
+        let mut locks = self.locks();
+        match locks.leases.get_mut(&id) {
+            Some(held) if held.token == lease.token && held.expires_at >= now => {
+                held.expires_at = expires_at;
+                Ok(held.clone())
+            }
+            _ => Err(StateError::LeaseLost(key.to_string())),
+        }
+    }
+
+    fn release_lease(&self, owner: &str, key: &str, lease: &Lease) {
+        let id = (owner.to_string(), key.to_string());
+
+        let mut locks = self.locks();
+        if locks.leases.get(&id).is_some_and(|held| held.token == lease.token) {
+            locks.leases.remove(&id);
+        }
+    }
+
+    // Broadcast a change to watchers, if there are any.
+    fn notify(&self, owner: &str, key: &str, event: ChangeEvent) {
+        if self.changes.receiver_count() == 0 {
+            return;
+        }
+        let change = Change {
+            owner: owner.to_string(),
+            key: key.to_string(),
+            event,
+        };
+        // sending fails only when the last watcher has just been dropped
+        let _ = self.changes.send(change);
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Owners> {
+        self.owners.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Owners> {
+        self.owners.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn locks(&self) -> MutexGuard<'_, Locks> {
+        self.locks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<C: Codec> StateStore for InMemoryStore<C> {
+    fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> impl Future<Output = Result<(), StateError>> + Send {
+        let result = self.codec.encode(state).map(|data| {
+            let entry = Entry {
+                data,
+                expires_at: state.expires_at,
+                version: state.version,
+            };
+            self.write().entry(owner.to_string()).or_default().insert(key.to_string(), entry);
+            self.notify(
+                owner,
+                key,
+                ChangeEvent::Put {
+                    version: state.version,
+                    expires_at: state.expires_at,
+                },
+            );
+        });
+        future::ready(result.map_err(Into::into))
+    }
+
+    fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<State<T>, StateError>> + Send {
+        // deserialize in the future as `T` is not necessarily `Send`
+        let data = self.data(owner, key);
+        let codec = &self.codec;
+        async move { Ok(codec.decode(&data?)?) }
+    }
+
+    fn purge(&self, owner: &str, key: &str) -> impl Future<Output = Result<(), StateError>> + Send {
+        let mut owners = self.write();
+        let mut removed = false;
+        if let Some(entries) = owners.get_mut(owner) {
+            removed = entries.remove(key).is_some();
+            if entries.is_empty() {
+                owners.remove(owner);
+            }
+        }
+        drop(owners);
+
+        if removed {
+            self.notify(owner, key, ChangeEvent::Removed);
+        }
+        future::ready(Ok(()))
+    }
+
+    fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<State<T>, StateError>> + Send {
+        let data = self.remove(owner, key);
+        let codec = &self.codec;
+        async move { Ok(codec.decode(&data?)?) }
+    }
+
+    fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> impl Future<Output = Result<u64, StateError>> + Send {
+        future::ready(self.put_versioned(owner, key, state))
+    }
+
+    fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> impl Future<Output = Result<Page, StateError>> + Send {
+        let start = match cursor {
+            Some(cursor) if cursor >= prefix => Bound::Excluded(cursor),
+            _ => Bound::Included(prefix),
+        };
+
+        let owners = self.read();
+        let page = owners.get(owner).map_or_else(Page::default, |entries| {
+            let keys = entries
+                .range::<str, _>((start, Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, entry)| KeyInfo {
+                    key: key.clone(),
+                    expires_at: entry.expires_at,
+                });
+            Page::from_sorted(keys, limit)
+        });
+        drop(owners);
+
+        future::ready(Ok(page))
+    }
+
+    fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> impl Future<Output = Result<Vec<Option<State<T>>>, StateError>> + Send {
+        let now = self.clock.now();
+        let owners = self.read();
+        let entries = owners.get(owner);
+        let data = keys
+            .iter()
+            .map(|key| {
+                entries
+                    .and_then(|entries| entries.get(*key))
+                    .filter(|entry| !entry.is_expired(now))
+                    .map(|entry| entry.data.clone())
+            })
+            .collect::<Vec<_>>();
+        drop(owners);
+
+        let states = data
+            .iter()
+            .map(|data| data.as_deref().map(|data| self.codec.decode(data)).transpose())
+            .collect::<anyhow::Result<Vec<_>>>();
+        future::ready(states.map_err(Into::into))
+    }
+
+    fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> impl Future<Output = Result<(), StateError>> + Send {
+        // encode every entry before taking the lock so the batch is applied
+        // in full or not at all
+        let encoded = entries
+            .iter()
+            .map(|(key, state)| {
+                let entry = Entry {
+                    data: self.codec.encode(state)?,
+                    expires_at: state.expires_at,
+                    version: state.version,
+                };
+                Ok(((*key).to_string(), entry))
+            })
+            .collect::<Result<Vec<_>, StateError>>();
+
+        let result = encoded.map(|encoded| {
+            if !encoded.is_empty() {
+                self.write().entry(owner.to_string()).or_default().extend(encoded);
+            }
+            for (key, state) in entries {
+                self.notify(
+                    owner,
+                    key,
+                    ChangeEvent::Put {
+                        version: state.version,
+                        expires_at: state.expires_at,
+                    },
+                );
+            }
+        });
+        future::ready(result)
+    }
+
+    fn purge_many(
+        &self, owner: &str, keys: &[&str],
+    ) -> impl Future<Output = Result<(), StateError>> + Send {
+        let mut owners = self.write();
+        let mut removed = Vec::new();
+        if let Some(entries) = owners.get_mut(owner) {
+            removed = keys.iter().filter(|key| entries.remove(**key).is_some()).collect();
+            if entries.is_empty() {
+                owners.remove(owner);
+            }
+        }
+        drop(owners);
+
+        for key in removed {
+            self.notify(owner, key, ChangeEvent::Removed);
+        }
+        future::ready(Ok(()))
+    }
+}
+
+impl<C: Codec> WatchStore for InMemoryStore<C> {
+    fn watch(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<impl Stream<Item = ChangeEvent> + Send + 'static, StateError>> + Send
+    {
+        // subscribe immediately so changes made before the future is polled
+        // are not missed
+        let receiver = self.changes.subscribe();
+        let changes = stream::unfold(
+            (receiver, owner.to_string(), key.to_string()),
+            |(mut receiver, owner, key)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(change) if change.owner == owner && change.key == key => {
+                            return Some((change.event, (receiver, owner, key)));
+                        }
+                        Err(RecvError::Lagged(_)) => {
+                            return Some((ChangeEvent::Lagged, (receiver, owner, key)));
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        );
+        future::ready(Ok(changes))
+    }
+}
+
+impl<C: Codec> LockStore for InMemoryStore<C> {
+    fn acquire(
+        &self, owner: &str, key: &str, ttl: TimeDelta,
+    ) -> impl Future<Output = Result<Lease, StateError>> + Send {
+        future::ready(self.acquire_lease(owner, key, ttl))
+    }
+
+    fn renew(
+        &self, owner: &str, key: &str, lease: &Lease, ttl: TimeDelta,
+    ) -> impl Future<Output = Result<Lease, StateError>> + Send {
+        future::ready(self.renew_lease(owner, key, lease, ttl))
+    }
+
+    fn release(
+        &self, owner: &str, key: &str, lease: &Lease,
+    ) -> impl Future<Output = Result<(), StateError>> + Send {
+        self.release_lease(owner, key, lease);
+        future::ready(Ok(()))
+    }
+}
+
+// The time a lease taken at `now` for `ttl` expires.
+fn lease_expiry(now: DateTime<Utc>, ttl: TimeDelta) -> Result<DateTime<Utc>, StateError> {
+    if ttl <= TimeDelta::zero() {
+        return Err(StateError::Internal(anyhow::anyhow!("lease ttl must be positive: {ttl}")));
+    }
+    now.checked_add_signed(ttl)
+        .ok_or_else(|| StateError::Internal(anyhow::anyhow!("lease ttl is out of range: {ttl}")))
+}
+
+async fn sweep_every(
+    owners: Weak<RwLock<Owners>>, locks: Weak<Mutex<Locks>>, clock: Arc<dyn Clock>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    // the first tick completes immediately
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let (Some(owners), Some(locks)) = (owners.upgrade(), locks.upgrade()) else {
+            return;
+        };
+        let now = clock.now();
+        locks.lock().unwrap_or_else(PoisonError::into_inner).prune(now);
+        sweep(&mut owners.write().unwrap_or_else(PoisonError::into_inner), now);
+    }
+}
+
+// Drop expired entries owner-by-owner, removing owners left with no entries.
+fn sweep(owners: &mut Owners, now: DateTime<Utc>) -> usize {
+    let mut removed = 0;
+
+    owners.retain(|_, entries| {
+        let before = entries.len();
+        entries.retain(|_, entry| !entry.is_expired(now));
+        removed += before - entries.len();
+        !entries.is_empty()
+    });
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use chrono::TimeDelta;
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn expired() {
+        let store = InMemoryStore::new();
+        let bob = State::with_ttl("bob".to_string(), TimeDelta::seconds(-1));
+
+        store.put("owner", "key", &bob).await.expect("should put");
+        let err = store.get::<String>("owner", "key").await.expect_err("should be expired");
+        assert!(matches!(err, StateError::Expired(_)));
+    }
+
+    #[tokio::test]
+    async fn clock() {
+        let clock = MockClock::default();
+        let store = InMemoryStore::new().with_clock(clock.clone());
+        store
+            .put("owner", "key", &State::with_ttl("alice".to_string(), TimeDelta::minutes(5)))
+            .await
+            .expect("should put");
+
+        clock.advance(TimeDelta::minutes(4));
+        store.get::<String>("owner", "key").await.expect("should get");
+
+        clock.advance(TimeDelta::minutes(2));
+        let err = store.get::<String>("owner", "key").await.expect_err("should be expired");
+        assert!(matches!(err, StateError::Expired(_)));
+        assert_eq!(store.sweep(), 1);
+    }
+
+    #[tokio::test]
+    async fn lock() {
+        let clock = MockClock::default();
+        let store = InMemoryStore::new().with_clock(clock.clone());
+        let ttl = TimeDelta::minutes(1);
+
+        let first = store.acquire("owner", "offer", ttl).await.expect("should acquire");
+        let err = store.acquire("owner", "offer", ttl).await.expect_err("should be locked");
+        assert!(matches!(err, StateError::Locked(_)));
+        store.acquire("other", "offer", ttl).await.expect("should acquire for other owner");
+
+        clock.advance(TimeDelta::seconds(30));
+        let renewed = store.renew("owner", "offer", &first, ttl).await.expect("should renew");
+        assert_eq!(renewed.token, first.token);
+        assert!(renewed.expires_at > first.expires_at);
+
+        clock.advance(TimeDelta::minutes(2));
+        let err = store.renew("owner", "offer", &first, ttl).await.expect_err("should be lost");
+        assert!(matches!(err, StateError::LeaseLost(_)));
+
+        let second = store.acquire("owner", "offer", ttl).await.expect("should acquire");
+        assert!(second.token > first.token);
+
+        // releasing a superseded lease leaves the current lease in place
+        store.release("owner", "offer", &first).await.expect("should release");
+        store.acquire("owner", "offer", ttl).await.expect_err("should be locked");
+
+        store.release("owner", "offer", &second).await.expect("should release");
+        store.acquire("owner", "offer", ttl).await.expect("should acquire");
+
+        store.acquire("owner", "max", TimeDelta::MAX).await.expect_err("should reject ttl");
+        store.acquire("owner", "zero", TimeDelta::zero()).await.expect_err("should reject ttl");
+        store
+            .renew("owner", "offer", &second, TimeDelta::seconds(-1))
+            .await
+            .expect_err("should reject ttl");
+    }
+
+    #[tokio::test]
+    async fn sweep_leases() {
+        let clock = MockClock::default();
+        let store = InMemoryStore::new().with_clock(clock.clone());
+        let ttl = TimeDelta::minutes(1);
+
+        store.acquire("owner", "stale", ttl).await.expect("should acquire");
+        clock.advance(TimeDelta::seconds(30));
+        store.acquire("owner", "live", ttl).await.expect("should acquire");
+
+        clock.advance(TimeDelta::seconds(45));
+        assert_eq!(store.sweep(), 0);
+
+        let leases = &store.locks().leases;
+        assert_eq!(leases.len(), 1);
+        assert!(leases.contains_key(&("owner".to_string(), "live".to_string())));
+    }
+
+    #[tokio::test]
+    async fn sweeper() {
+        let store = InMemoryStore::new().with_sweeper(Duration::from_millis(10));
+        store
+            .put("owner", "live", &State::with_ttl("live".to_string(), TimeDelta::minutes(5)))
+            .await
+            .expect("should put");
+        store
+            .put("owner", "dead", &State::with_ttl("dead".to_string(), TimeDelta::seconds(-1)))
+            .await
+            .expect("should put");
+        store
+            .put("other", "dead", &State::with_ttl("dead".to_string(), TimeDelta::seconds(-1)))
+            .await
+            .expect("should put");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let owners = store.read().clone();
+        assert_eq!(owners.len(), 1);
+        assert!(owners["owner"].contains_key("live"));
+        assert!(!owners["owner"].contains_key("dead"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn conformance() {
+        crate::state::testing::conformance(InMemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn watch() {
+        let store = InMemoryStore::new();
+        let changes = store.watch("owner", "key").await.expect("should watch");
+        let mut changes = pin!(changes);
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        store.put("other", "key", &alice).await.expect("should put");
+        store.put("owner", "key", &alice).await.expect("should put");
+        let expected = ChangeEvent::Put {
+            version: 0,
+            expires_at: alice.expires_at,
+        };
+        assert_eq!(changes.next().await, Some(expected));
+
+        store.purge("owner", "key").await.expect("should purge");
+        assert_eq!(changes.next().await, Some(ChangeEvent::Removed));
+
+        drop(store);
+        assert_eq!(changes.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn watch_lagged() {
+        let store = InMemoryStore::new();
+        let changes = store.watch("owner", "key").await.expect("should watch");
+        let mut changes = pin!(changes);
+        let alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        for i in 0..=CHANGE_CAPACITY {
+            store.put("owner", &format!("other-{i}"), &alice).await.expect("should put");
+        }
+        store.put("owner", "key", &alice).await.expect("should put");
+
+        assert_eq!(changes.next().await, Some(ChangeEvent::Lagged));
+        assert!(matches!(changes.next().await, Some(ChangeEvent::Put { .. })));
+    }
+}
@@ -0,0 +1,643 @@
+//! # Scoped Store
+//!
+//! Binds a [`StateStore`] to a single owner (tenant), optionally enforcing
+//! quotas on the number of keys and total bytes stored by that owner.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use anyhow::{Result, bail};
+use futures_core::Stream;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::state::{ChangeEvent, Page, State, StateError, StateStore, WatchStore};
+
+/// The owner (tenant) state is stored for.
+///
+/// An `Owner` is a non-empty string without control characters. It
+/// dereferences to `&str` so can be passed directly to [`StateStore`]
+/// methods.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Owner(String);
+
+impl Owner {
+    /// Create a new `Owner`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the owner is empty or contains control characters.
+    pub fn new(owner: impl Into<String>) -> Result<Self> {
+        let owner = owner.into();
+        if owner.is_empty() {
+            bail!("owner cannot be empty");
+        }
+        if owner.chars().any(char::is_control) {
+            bail!("owner cannot contain control characters");
+        }
+        Ok(Self(owner))
+    }
+
+    /// Returns the owner as a string slice.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Owner {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Owner {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for Owner {
+    type Error = anyhow::Error;
+
+    fn try_from(owner: String) -> Result<Self> {
+        Self::new(owner)
+    }
+}
+
+impl TryFrom<&str> for Owner {
+    type Error = anyhow::Error;
+
+    fn try_from(owner: &str) -> Result<Self> {
+        Self::new(owner)
+    }
+}
+
+impl From<Owner> for String {
+    fn from(owner: Owner) -> Self {
+        owner.0
+    }
+}
+
+/// Limits on the state a single owner may hold in a [`ScopedStore`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// The maximum number of keys.
+    pub max_keys: Option<usize>,
+
+    /// The maximum total size, in bytes, of state measured as JSON.
+    ///
+    /// This approximates the space used by the underlying store, which may
+    /// hold more or fewer bytes once its codec, compression or encryption
+    /// has been applied.
+    pub max_bytes: Option<usize>,
+}
+
+/// A [`StateStore`] bound to a single owner.
+///
+/// The key-only methods operate on the bound owner. The [`StateStore`]
+/// implementation rejects any request for a different owner, so a
+/// `ScopedStore` can be handed to code expecting a `StateStore` without
+/// exposing other tenants' state.
+///
+/// Usage is calculated from the underlying store when a quota is set, then
+/// accounted for writes made through the store and shared between clones.
+/// State written by other means, such as by another replica, is not counted
+/// until [`ScopedStore::sync_usage`] is called again. Expired state continues
+/// to count until it is purged.
+///
+/// Calculating usage reads every key held by the owner, so a quota-enforcing
+/// store should be created once per owner and cloned, rather than created
+/// for each request.
+#[derive(Clone, Debug)]
+pub struct ScopedStore<S> {
+    store: S,
+    owner: Owner,
+    quota: Quota,
+    usage: Arc<Mutex<Usage>>,
+}
+
+/// The size recorded for each key, along with the reservation that recorded
+/// it so a failed write only rolls back its own reservation.
+#[derive(Debug, Default)]
+struct Usage {
+    sizes: HashMap<String, Reservation>,
+    next: u64,
+}
+
+/// The size recorded for a key by a write.
+#[derive(Clone, Copy, Debug)]
+struct Reservation {
+    size: usize,
+    id: u64,
+}
+
+impl<S: StateStore> ScopedStore<S> {
+    /// Bind `store` to `owner`.
+    pub fn new(store: S, owner: Owner) -> Self {
+        Self {
+            store,
+            owner,
+            quota: Quota::default(),
+            usage: Arc::default(),
+        }
+    }
+
+    /// Enforce the provided quota on writes, first calculating the owner's
+    /// current usage from the underlying store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the owner's keys cannot be listed or read.
+    pub async fn with_quota(mut self, quota: Quota) -> Result<Self, StateError> {
+        self.sync_usage().await?;
+        self.quota = quota;
+        Ok(self)
+    }
+
+    /// The owner the store is bound to.
+    pub const fn owner(&self) -> &Owner {
+        &self.owner
+    }
+
+    /// The number of keys and total bytes currently accounted to the owner.
+    ///
+    /// Usage reflects only writes made through the store until a quota is
+    /// set or [`ScopedStore::sync_usage`] is called.
+    pub fn usage(&self) -> (usize, usize) {
+        let usage = self.lock_usage();
+        (usage.sizes.len(), usage.sizes.values().map(|reserved| reserved.size).sum())
+    }
+
+    /// Recalculate usage from the state held by the underlying store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the owner's keys cannot be listed or read.
+    pub async fn sync_usage(&self) -> Result<(), StateError> {
+        let mut sizes = HashMap::new();
+        for info in self.store.scan(&self.owner, "").await? {
+            // expired state cannot be read but still occupies the store
+            let size = match self.store.get::<Value>(&self.owner, &info.key).await {
+                Ok(state) => serde_json::to_vec(&state).map_err(anyhow::Error::from)?.len(),
+                Err(StateError::NotFound(_) | StateError::Expired(_)) => 0,
+                Err(e) => return Err(e),
+            };
+            sizes.insert(info.key, Reservation { size, id: 0 });
+        }
+        self.lock_usage().sizes = sizes;
+        Ok(())
+    }
+
+    /// Store state for the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the write would exceed the quota or the underlying
+    /// store fails.
+    pub async fn put<T: Serialize + Sync>(
+        &self, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        let (id, previous) = self.reserve(key, state)?;
+        let result = self.store.put(&self.owner, key, state).await;
+        self.settle(key, id, previous, result.is_ok());
+        result
+    }
+
+    /// Retrieve state for the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state cannot be found or has expired.
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<State<T>, StateError> {
+        self.store.get(&self.owner, key).await
+    }
+
+    /// Remove state for the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying store fails.
+    pub async fn purge(&self, key: &str) -> Result<(), StateError> {
+        self.store.purge(&self.owner, key).await?;
+        self.lock_usage().sizes.remove(key);
+        Ok(())
+    }
+
+    /// Retrieve and remove state for the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the state cannot be found or has expired.
+    pub async fn take<T: DeserializeOwned + Send>(
+        &self, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let state = self.store.take(&self.owner, key).await;
+        if matches!(state, Ok(_) | Err(StateError::NotFound(_) | StateError::Expired(_))) {
+            self.lock_usage().sizes.remove(key);
+        }
+        state
+    }
+
+    /// Store state for the bound owner if its version matches the stored
+    /// version.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the write would exceed the quota, the versions do
+    /// not match, or the underlying store fails.
+    pub async fn put_if_version<T: Serialize + Sync>(
+        &self, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        let (id, previous) = self.reserve(key, state)?;
+        let result = self.store.put_if_version(&self.owner, key, state).await;
+        self.settle(key, id, previous, result.is_ok());
+        result
+    }
+
+    /// List keys stored for the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying store fails.
+    pub async fn list(
+        &self, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        self.store.list(&self.owner, prefix, cursor, limit).await
+    }
+
+    /// Retrieve state for several keys of the bound owner. Missing and
+    /// expired state is returned as `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying store fails.
+    pub async fn get_many<T: DeserializeOwned + Send>(
+        &self, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        self.store.get_many(&self.owner, keys).await
+    }
+
+    /// Store state for several keys of the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the batch would exceed the quota or the underlying
+    /// store fails.
+    pub async fn put_many<T: Serialize + Sync>(
+        &self, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        let mut reserved = Vec::with_capacity(entries.len());
+        for (key, state) in entries {
+            match self.reserve(key, state) {
+                Ok((id, previous)) => reserved.push((*key, id, previous)),
+                Err(e) => {
+                    self.rollback(&reserved);
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = self.store.put_many(&self.owner, entries).await;
+        if result.is_err() {
+            self.rollback(&reserved);
+        }
+        result
+    }
+
+    /// Remove state for several keys of the bound owner.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying store fails.
+    pub async fn purge_many(&self, keys: &[&str]) -> Result<(), StateError> {
+        self.store.purge_many(&self.owner, keys).await?;
+        let mut usage = self.lock_usage();
+        for key in keys {
+            usage.sizes.remove(*key);
+        }
+        drop(usage);
+        Ok(())
+    }
+
+    // Check the write against the quota and record its size, measured as
+    // JSON, returning the reservation's id and the reservation it replaced.
+    fn reserve<T: Serialize>(
+        &self, key: &str, state: &State<T>,
+    ) -> Result<(u64, Option<Reservation>), StateError> {
+        let size = serde_json::to_vec(state).map_err(anyhow::Error::from)?.len();
+        let mut usage = self.lock_usage();
+
+        let previous = usage.sizes.get(key).copied();
+        let keys = usage.sizes.len() + usize::from(previous.is_none());
+        let bytes = usage.sizes.values().map(|reserved| reserved.size).sum::<usize>()
+            - previous.map_or(0, |reserved| reserved.size)
+            + size;
+
+        if let Some(max_keys) = self.quota.max_keys
+            && keys > max_keys
+        {
+            let reason = format!("{}: {keys} keys exceeds {max_keys}", self.owner);
+            return Err(StateError::QuotaExceeded(reason));
+        }
+        if let Some(max_bytes) = self.quota.max_bytes
+            && bytes > max_bytes
+        {
+            let reason = format!("{}: {bytes} bytes exceeds {max_bytes}", self.owner);
+            return Err(StateError::QuotaExceeded(reason));
+        }
+
+        usage.next += 1;
+        let id = usage.next;
+        usage.sizes.insert(key.to_string(), Reservation { size, id });
+        drop(usage);
+        Ok((id, previous))
+    }
+
+    // Roll back a reservation if the write failed, unless a later write has
+    // since replaced it.
+    fn settle(&self, key: &str, id: u64, previous: Option<Reservation>, written: bool) {
+        if written {
+            return;
+        }
+        let mut usage = self.lock_usage();
+        if usage.sizes.get(key).is_none_or(|reserved| reserved.id != id) {
+            return;
+        }
+        match previous {
+            Some(reserved) => usage.sizes.insert(key.to_string(), reserved),
+            None => usage.sizes.remove(key),
+        };
+    }
+
+    // Roll back a batch of reservations, most recent first.
+    fn rollback(&self, reserved: &[(&str, u64, Option<Reservation>)]) {
+        for (key, id, previous) in reserved.iter().rev() {
+            self.settle(key, *id, *previous, false);
+        }
+    }
+
+    fn lock_usage(&self) -> MutexGuard<'_, Usage> {
+        self.usage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn check_owner(&self, owner: &str) -> Result<(), StateError> {
+        if owner != self.owner.as_str() {
+            return Err(StateError::Forbidden("store is scoped to a different owner".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl<S: StateStore> StateStore for ScopedStore<S> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        self.check_owner(owner)?;
+        Self::put(self, key, state).await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        self.check_owner(owner)?;
+        Self::get(self, key).await
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        self.check_owner(owner)?;
+        Self::purge(self, key).await
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        self.check_owner(owner)?;
+        Self::take(self, key).await
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        self.check_owner(owner)?;
+        Self::put_if_version(self, key, state).await
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        self.check_owner(owner)?;
+        Self::list(self, prefix, cursor, limit).await
+    }
+
+    async fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        self.check_owner(owner)?;
+        Self::get_many(self, keys).await
+    }
+
+    async fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        self.check_owner(owner)?;
+        Self::put_many(self, entries).await
+    }
+
+    async fn purge_many(&self, owner: &str, keys: &[&str]) -> Result<(), StateError> {
+        self.check_owner(owner)?;
+        Self::purge_many(self, keys).await
+    }
+}
+
+impl<S: WatchStore> WatchStore for ScopedStore<S> {
+    async fn watch(
+        &self, owner: &str, key: &str,
+    ) -> Result<impl Stream<Item = ChangeEvent> + Send + 'static, StateError> {
+        self.check_owner(owner)?;
+        self.store.watch(owner, key).await
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    fn owner(owner: &str) -> Owner {
+        Owner::new(owner).expect("should be valid")
+    }
+
+    #[test]
+    fn owner_validation() {
+        Owner::new("").expect_err("should reject empty");
+        Owner::new("alice\n").expect_err("should reject control characters");
+        serde_json::from_str::<Owner>(r#""""#).expect_err("should reject empty");
+        assert_eq!(&*owner("alice"), "alice");
+    }
+
+    #[tokio::test]
+    async fn isolation() {
+        let inner = InMemoryStore::new();
+        let alice = ScopedStore::new(inner.clone(), owner("alice"));
+        let state = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        alice.put("key", &state).await.expect("should put");
+        assert_eq!(inner.get::<String>("alice", "key").await.expect("should get"), state);
+        StateStore::get::<String>(&alice, "bob", "key").await.expect_err("should reject owner");
+    }
+
+    #[tokio::test]
+    async fn quota() {
+        let quota = Quota {
+            max_keys: Some(2),
+            max_bytes: None,
+        };
+        let store = ScopedStore::new(InMemoryStore::new(), owner("alice"))
+            .with_quota(quota)
+            .await
+            .expect("should sync usage");
+        let state = State::with_ttl("body".to_string(), TimeDelta::minutes(5));
+
+        store.put("one", &state).await.expect("should put");
+        store.put("two", &state).await.expect("should put");
+        store.put("two", &state).await.expect("should overwrite");
+        let err = store.put("three", &state).await.expect_err("should exceed key quota");
+        assert!(matches!(err, StateError::QuotaExceeded(_)));
+
+        store.purge("one").await.expect("should purge");
+        store.put("three", &state).await.expect("should put");
+        assert_eq!(store.usage().0, 2);
+
+        let size = store.usage().1 / 2;
+        let store = store
+            .with_quota(Quota {
+                max_keys: None,
+                max_bytes: Some(size * 2),
+            })
+            .await
+            .expect("should sync usage");
+        store.put("four", &state).await.expect_err("should exceed byte quota");
+
+        // a batch is rejected in full
+        let batch = [("four", state.clone()), ("five", state.clone())];
+        store.purge("three").await.expect("should purge");
+        store.put_many(&batch).await.expect_err("should exceed byte quota");
+        assert_eq!(store.usage(), (1, size));
+    }
+
+    #[tokio::test]
+    async fn existing_usage() {
+        let inner = InMemoryStore::new();
+        let state = State::with_ttl("body".to_string(), TimeDelta::minutes(5));
+        inner.put("alice", "one", &state).await.expect("should put");
+
+        let quota = Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        };
+        let store = ScopedStore::new(inner, owner("alice"))
+            .with_quota(quota)
+            .await
+            .expect("should sync usage");
+        assert_eq!(store.usage().0, 1);
+        store.put("two", &state).await.expect_err("should count existing keys");
+    }
+
+    // Fails every call while `down` is set.
+    #[derive(Clone, Default)]
+    struct Flaky {
+        inner: InMemoryStore,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Flaky {
+        fn check(&self) -> Result<(), StateError> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(StateError::Unavailable(anyhow::anyhow!("store is down")));
+            }
+            Ok(())
+        }
+    }
+
+    impl StateStore for Flaky {
+        async fn put<T: Serialize + Sync>(
+            &self, owner: &str, key: &str, state: &State<T>,
+        ) -> Result<(), StateError> {
+            self.check()?;
+            self.inner.put(owner, key, state).await
+        }
+
+        async fn get<T: DeserializeOwned>(
+            &self, owner: &str, key: &str,
+        ) -> Result<State<T>, StateError> {
+            self.check()?;
+            self.inner.get(owner, key).await
+        }
+
+        async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+            self.check()?;
+            self.inner.purge(owner, key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_writes() {
+        let flaky = Flaky::default();
+        let store = ScopedStore::new(flaky.clone(), owner("alice"));
+        let state = State::with_ttl("body".to_string(), TimeDelta::minutes(5));
+
+        store.put("key", &state).await.expect("should put");
+        let usage = store.usage();
+        assert_eq!(usage.0, 1);
+
+        // a failed write or take leaves the recorded usage in place
+        flaky.down.store(true, Ordering::Relaxed);
+        let larger = State::with_ttl("larger body".to_string(), TimeDelta::minutes(5));
+        store.put("key", &larger).await.expect_err("should be unavailable");
+        assert_eq!(store.usage(), usage);
+        store.take::<String>("key").await.expect_err("should be unavailable");
+        assert_eq!(store.usage(), usage);
+
+        flaky.down.store(false, Ordering::Relaxed);
+        store.take::<String>("key").await.expect("should take");
+        assert_eq!(store.usage(), (0, 0));
+        store.take::<String>("key").await.expect_err("should not be found");
+    }
+
+    #[test]
+    fn concurrent_rollback() {
+        let store = ScopedStore::new(InMemoryStore::new(), owner("alice"));
+        let first = State::with_ttl("first".to_string(), TimeDelta::minutes(5));
+        let second = State::with_ttl("second body".to_string(), TimeDelta::minutes(5));
+
+        let (first_id, first_previous) = store.reserve("key", &first).expect("should reserve");
+        let reserved = store.usage();
+        let (second_id, second_previous) = store.reserve("key", &second).expect("should reserve");
+        let usage = store.usage();
+
+        // the first write failing does not discard the second's reservation
+        store.settle("key", first_id, first_previous, false);
+        assert_eq!(store.usage(), usage);
+
+        // the second write failing restores the reservation it replaced
+        store.settle("key", second_id, second_previous, false);
+        assert_eq!(store.usage(), reserved);
+    }
+}
@@ -0,0 +1,418 @@
+//! # SQLite Store
+//!
+//! A [`StateStore`] backed by an embedded SQLite database.
+//!
+//! State is held in a single `state` table keyed by owner and key. The
+//! serialized state is stored alongside its expiry time, which is indexed so
+//! expired entries can be purged efficiently. State is serialized using the
+//! store's [`Codec`](crate::state::Codec), JSON by default.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{Result, anyhow};
+use chrono::DateTime;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::clock::{Clock, SystemClock};
+use crate::state::{Codec, Json, KeyInfo, Page, State, StateError, StateStore};
+
+const UPSERT: &str = "
+    INSERT INTO state (owner, key, body, expires_at, version) VALUES (?1, ?2, ?3, ?4, ?5)
+    ON CONFLICT (owner, key) DO UPDATE SET
+        body = excluded.body,
+        expires_at = excluded.expires_at,
+        version = excluded.version
+";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS state (
+        owner TEXT NOT NULL,
+        key TEXT NOT NULL,
+        body BLOB NOT NULL,
+        expires_at INTEGER NOT NULL,
+        version INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (owner, key)
+    );
+    CREATE INDEX IF NOT EXISTS state_expires_at ON state (expires_at);
+";
+
+/// A SQLite backed [`StateStore`].
+///
+/// Cloning the store is cheap and clones share the same connection. Queries
+/// are run on Tokio's blocking thread pool.
+#[derive(Clone, Debug)]
+pub struct SqliteStore<C = Json> {
+    conn: Arc<Mutex<Connection>>,
+    clock: Arc<dyn Clock>,
+    codec: C,
+}
+
+impl SqliteStore {
+    /// Open (or create) a database at the provided path.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be opened or the schema cannot
+    /// be created.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    /// Open a new in-memory database.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database cannot be opened or the schema cannot
+    /// be created.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    /// Create a store using an existing connection, creating the `state`
+    /// table if it does not already exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the schema cannot be created.
+    pub fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            clock: Arc::new(SystemClock),
+            codec: Json,
+        })
+    }
+}
+
+impl<C: Codec> SqliteStore<C> {
+    /// Use the provided clock to determine when entries have expired.
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Serialize state using the provided codec.
+    ///
+    /// Existing rows are not re-encoded, so a database should always be
+    /// opened with the codec its rows were written with.
+    #[must_use]
+    pub fn with_codec<D: Codec>(self, codec: D) -> SqliteStore<D> {
+        SqliteStore {
+            conn: self.conn,
+            clock: self.clock,
+            codec,
+        }
+    }
+
+    /// Remove all expired entries, returning the number removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database query fails.
+    pub async fn purge_expired(&self) -> Result<usize, StateError> {
+        let now = self.clock.now().timestamp_micros();
+        self.call(move |conn| {
+            let removed = conn.execute("DELETE FROM state WHERE expires_at < ?1", params![now])?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    // Run `f` against the connection on the blocking thread pool.
+    async fn call<R, F>(&self, f: F) -> Result<R, StateError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Connection) -> Result<R, StateError> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&conn)
+        })
+        .await
+        .map_err(|e| StateError::Unavailable(e.into()))?
+    }
+}
+
+impl<C: Codec> StateStore for SqliteStore<C> {
+    async fn put<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<(), StateError> {
+        let body = self.codec.encode(state)?;
+        let expires_at = state.expires_at.timestamp_micros();
+        let version = i64::try_from(state.version).map_err(anyhow::Error::from)?;
+        let (owner, key) = (owner.to_string(), key.to_string());
+
+        self.call(move |conn| {
+            conn.execute(UPSERT, params![owner, key, body, expires_at, version])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let (owner, key) = (owner.to_string(), key.to_string());
+        let now = self.clock.now().timestamp_micros();
+
+        let body = self
+            .call(move |conn| {
+                let row = conn
+                    .query_row(
+                        "SELECT body, expires_at FROM state WHERE owner = ?1 AND key = ?2",
+                        params![owner, key],
+                        |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)),
+                    )
+                    .optional()?;
+                unexpired(row, &key, now)
+            })
+            .await?;
+
+        Ok(self.codec.decode(&body)?)
+    }
+
+    async fn purge(&self, owner: &str, key: &str) -> Result<(), StateError> {
+        let (owner, key) = (owner.to_string(), key.to_string());
+        self.call(move |conn| {
+            conn.execute("DELETE FROM state WHERE owner = ?1 AND key = ?2", params![owner, key])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take<T: DeserializeOwned + Send>(
+        &self, owner: &str, key: &str,
+    ) -> Result<State<T>, StateError> {
+        let (owner, key) = (owner.to_string(), key.to_string());
+        let now = self.clock.now().timestamp_micros();
+
+        let body = self
+            .call(move |conn| {
+                let row = conn
+                    .query_row(
+                        "DELETE FROM state WHERE owner = ?1 AND key = ?2 RETURNING body, expires_at",
+                        params![owner, key],
+                        |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)),
+                    )
+                    .optional()?;
+                unexpired(row, &key, now)
+            })
+            .await?;
+
+        Ok(self.codec.decode(&body)?)
+    }
+
+    async fn list(
+        &self, owner: &str, prefix: &str, cursor: Option<&str>, limit: usize,
+    ) -> Result<Page, StateError> {
+        let (owner, prefix) = (owner.to_string(), prefix.to_string());
+        let cursor = cursor.map(ToString::to_string);
+        // fetch one more row than requested to detect a following page
+        let fetch = i64::try_from(limit.max(1).saturating_add(1)).unwrap_or(i64::MAX);
+
+        let rows = self
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT key, expires_at FROM state
+                    WHERE owner = ?1 AND substr(key, 1, length(?2)) = ?2
+                        AND (?3 IS NULL OR key > ?3)
+                    ORDER BY key LIMIT ?4",
+                )?;
+                let rows = stmt
+                    .query_map(params![owner, prefix, cursor, fetch], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(rows)
+            })
+            .await?;
+
+        let keys = rows
+            .into_iter()
+            .map(|(key, expires_at)| {
+                let Some(expires_at) = DateTime::from_timestamp_micros(expires_at) else {
+                    return Err(anyhow!("invalid expiry for key: {key}").into());
+                };
+                Ok(KeyInfo { key, expires_at })
+            })
+            .collect::<Result<Vec<_>, StateError>>()?;
+
+        Ok(Page::from_sorted(keys, limit))
+    }
+
+    async fn put_if_version<T: Serialize + Sync>(
+        &self, owner: &str, key: &str, state: &State<T>,
+    ) -> Result<u64, StateError> {
+        let expected = i64::try_from(state.version).map_err(anyhow::Error::from)?;
+        let version = expected + 1;
+        let body = self.codec.encode(&state.with_version(version.cast_unsigned()))?;
+        let expires_at = state.expires_at.timestamp_micros();
+        let (owner, key) = (owner.to_string(), key.to_string());
+        let now = self.clock.now().timestamp_micros();
+
+        self.call(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let current = tx
+                .query_row(
+                    "SELECT version FROM state WHERE owner = ?1 AND key = ?2 AND expires_at >= ?3",
+                    params![owner, key, now],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?
+                .unwrap_or_default();
+            if current != expected {
+                return Err(StateError::Conflict {
+                    key,
+                    expected: expected.cast_unsigned(),
+                    found: current.cast_unsigned(),
+                });
+            }
+            tx.execute(UPSERT, params![owner, key, body, expires_at, version])?;
+            tx.commit()?;
+            Ok(version.cast_unsigned())
+        })
+        .await
+    }
+
+    async fn get_many<T: DeserializeOwned + Send>(
+        &self, owner: &str, keys: &[&str],
+    ) -> Result<Vec<Option<State<T>>>, StateError> {
+        let owner = owner.to_string();
+        let keys = keys.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let now = self.clock.now().timestamp_micros();
+
+        let bodies = self
+            .call(move |conn| {
+                let mut stmt = conn
+                    .prepare("SELECT body, expires_at FROM state WHERE owner = ?1 AND key = ?2")?;
+                keys.iter()
+                    .map(|key| {
+                        let row = stmt
+                            .query_row(params![owner, key], |row| {
+                                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+                            })
+                            .optional()?;
+                        Ok(row.filter(|(_, expires_at)| *expires_at >= now).map(|(body, _)| body))
+                    })
+                    .collect::<Result<Vec<_>, StateError>>()
+            })
+            .await?;
+
+        let states = bodies
+            .iter()
+            .map(|body| body.as_deref().map(|body| self.codec.decode(body)).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(states)
+    }
+
+    async fn put_many<T: Serialize + Sync>(
+        &self, owner: &str, entries: &[(&str, State<T>)],
+    ) -> Result<(), StateError> {
+        let rows = entries
+            .iter()
+            .map(|(key, state)| {
+                let version = i64::try_from(state.version).map_err(anyhow::Error::from)?;
+                let body = self.codec.encode(state)?;
+                Ok(((*key).to_string(), body, state.expires_at.timestamp_micros(), version))
+            })
+            .collect::<Result<Vec<_>, StateError>>()?;
+        let owner = owner.to_string();
+
+        self.call(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let mut stmt = tx.prepare(UPSERT)?;
+            for (key, body, expires_at, version) in rows {
+                stmt.execute(params![owner, key, body, expires_at, version])?;
+            }
+            drop(stmt);
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn purge_many(&self, owner: &str, keys: &[&str]) -> Result<(), StateError> {
+        let owner = owner.to_string();
+        let keys = keys.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        self.call(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let mut stmt = tx.prepare("DELETE FROM state WHERE owner = ?1 AND key = ?2")?;
+            for key in keys {
+                stmt.execute(params![owner, key])?;
+            }
+            drop(stmt);
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+// Returns the body of a `(body, expires_at)` row if the row exists and has
+// not expired as at `now`.
+fn unexpired(row: Option<(Vec<u8>, i64)>, key: &str, now: i64) -> Result<Vec<u8>, StateError> {
+    match row {
+        None => Err(StateError::NotFound(key.to_string())),
+        Some((_, expires_at)) if expires_at < now => Err(StateError::Expired(key.to_string())),
+        Some((body, _)) => Ok(body),
+    }
+}
+
+impl From<rusqlite::Error> for StateError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Unavailable(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn purge_expired() {
+        let store = SqliteStore::open_in_memory().expect("should open");
+
+        let live = State::with_ttl("live".to_string(), TimeDelta::minutes(5));
+        store.put("owner", "live", &live).await.expect("should put");
+        store
+            .put("owner", "dead", &State::with_ttl("dead".to_string(), TimeDelta::seconds(-1)))
+            .await
+            .expect("should put");
+
+        let err = store.get::<String>("owner", "dead").await.expect_err("should be expired");
+        assert!(matches!(err, StateError::Expired(_)));
+
+        assert_eq!(store.purge_expired().await.expect("should purge"), 1);
+        assert_eq!(store.get::<String>("owner", "live").await.expect("should get"), live);
+    }
+
+    #[cfg(all(feature = "compression", feature = "msgpack"))]
+    #[tokio::test]
+    async fn codec() {
+        use crate::state::{Compressed, MessagePack};
+
+        let store = SqliteStore::open_in_memory()
+            .expect("should open")
+            .with_codec(Compressed::new(MessagePack));
+        let mut alice = State::with_ttl("alice".to_string(), TimeDelta::minutes(5));
+
+        alice.version = store.put_if_version("owner", "key", &alice).await.expect("should put");
+        assert_eq!(store.get::<String>("owner", "key").await.expect("should get"), alice);
+        assert_eq!(store.take::<String>("owner", "key").await.expect("should take"), alice);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn conformance() {
+        crate::state::testing::conformance(SqliteStore::open_in_memory().expect("should open"))
+            .await;
+    }
+}
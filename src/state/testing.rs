@@ -0,0 +1,262 @@
+//! # Testing
+//!
+//! A conformance suite for [`StateStore`] implementations.
+//!
+//! [`conformance`] exercises the behaviour every store is expected to share,
+//! so new backends can be checked against the same expectations as the
+//! stores in this crate:
+//!
+//! ```rust,ignore
+//! #[tokio::test]
+//! async fn conformance() {
+//!     credibil_core::state::testing::conformance(MyStore::new()).await;
+//! }
+//! ```
+
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{State, StateError, StateStore};
+
+// Number of concurrent writers used to check versioned writes, and the
+// number of attempts each is allowed before giving up.
+const WRITERS: u64 = 8;
+const ATTEMPTS: usize = 1_000;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+struct Body {
+    name: String,
+    scopes: Vec<String>,
+    count: u64,
+}
+
+/// Run the conformance suite against `store`, panicking on the first
+/// failure.
+///
+/// The suite writes state for owners prefixed with `conformance-` and should
+/// be run against an otherwise empty store.
+///
+/// # Panics
+///
+/// Panics if the store does not behave as expected.
+pub async fn conformance<S: StateStore + Clone + 'static>(store: S) {
+    round_trip(&store).await;
+    overwrite(&store).await;
+    missing(&store).await;
+    expiry(&store).await;
+    isolation(&store).await;
+    take(&store).await;
+    versioned(&store).await;
+    list(&store).await;
+    batch(&store).await;
+    concurrency(&store).await;
+}
+
+fn body(name: &str) -> Body {
+    Body {
+        name: name.to_string(),
+        scopes: vec!["openid".to_string(), "profile".to_string()],
+        count: 0,
+    }
+}
+
+async fn round_trip(store: &impl StateStore) {
+    let owner = "conformance-round-trip";
+    let mut alice = State::with_ttl(body("alice"), TimeDelta::minutes(5));
+    alice.version = 3;
+
+    store.put(owner, "key", &alice).await.expect("should put");
+    let got: State<Body> = store.get(owner, "key").await.expect("should get");
+    assert_eq!(got, alice, "get should return the state put");
+
+    store.purge(owner, "key").await.expect("should purge");
+    store.get::<Body>(owner, "key").await.expect_err("purged state should not be found");
+}
+
+async fn overwrite(store: &impl StateStore) {
+    let owner = "conformance-overwrite";
+    let bob = State::with_ttl(body("bob"), TimeDelta::minutes(5));
+
+    store
+        .put(owner, "key", &State::with_ttl(body("alice"), TimeDelta::minutes(5)))
+        .await
+        .expect("should put");
+    store.put(owner, "key", &bob).await.expect("should overwrite");
+    assert_eq!(store.get::<Body>(owner, "key").await.expect("should get"), bob);
+
+    store.purge(owner, "key").await.expect("should purge");
+}
+
+async fn missing(store: &impl StateStore) {
+    let owner = "conformance-missing";
+
+    let err = store.get::<Body>(owner, "missing").await.expect_err("should not be found");
+    assert!(matches!(err, StateError::NotFound(_)), "get should return not found: {err}");
+    let err = store.take::<Body>(owner, "missing").await.expect_err("should not be taken");
+    assert!(matches!(err, StateError::NotFound(_)), "take should return not found: {err}");
+    store.purge(owner, "missing").await.expect("purging missing state should succeed");
+}
+
+async fn expiry(store: &impl StateStore) {
+    let owner = "conformance-expiry";
+    let expired = State::with_ttl(body("expired"), TimeDelta::seconds(-1));
+
+    store.put(owner, "key", &expired).await.expect("should put");
+    let err = store.get::<Body>(owner, "key").await.expect_err("should not be returned");
+    assert!(matches!(err, StateError::Expired(_)), "get should return expired: {err}");
+    let err = store.take::<Body>(owner, "key").await.expect_err("should not be taken");
+    assert!(matches!(err, StateError::Expired(_)), "take should return expired: {err}");
+
+    // expired state is treated as version 0
+    store
+        .put(
+            owner,
+            "versioned",
+            &State {
+                version: 4,
+                ..expired
+            },
+        )
+        .await
+        .expect("should put");
+    let live = State::with_ttl(body("live"), TimeDelta::minutes(5));
+    let version =
+        store.put_if_version(owner, "versioned", &live).await.expect("should replace expired");
+    assert_eq!(version, 1, "expired state should be treated as version 0");
+
+    store.purge(owner, "key").await.expect("should purge");
+    store.purge(owner, "versioned").await.expect("should purge");
+}
+
+async fn isolation(store: &impl StateStore) {
+    let (alice, bob) = ("conformance-alice", "conformance-bob");
+
+    store
+        .put(alice, "key", &State::with_ttl(body("alice"), TimeDelta::minutes(5)))
+        .await
+        .expect("should put");
+    store.get::<Body>(bob, "key").await.expect_err("state should be scoped to its owner");
+
+    store.purge(bob, "key").await.expect("should purge");
+    store.get::<Body>(alice, "key").await.expect("purge should be scoped to its owner");
+    store.purge(alice, "key").await.expect("should purge");
+}
+
+async fn take(store: &impl StateStore) {
+    let owner = "conformance-take";
+    let alice = State::with_ttl(body("alice"), TimeDelta::minutes(5));
+
+    store.put(owner, "key", &alice).await.expect("should put");
+    assert_eq!(store.take::<Body>(owner, "key").await.expect("should take"), alice);
+    store.get::<Body>(owner, "key").await.expect_err("taken state should be removed");
+}
+
+async fn versioned(store: &impl StateStore) {
+    let owner = "conformance-versioned";
+    let mut alice = State::with_ttl(body("alice"), TimeDelta::minutes(5));
+
+    alice.version = store.put_if_version(owner, "key", &alice).await.expect("should put");
+    assert_eq!(alice.version, 1, "missing state should be treated as version 0");
+
+    let stale = alice.clone();
+    alice.version = store.put_if_version(owner, "key", &alice).await.expect("should put");
+    assert_eq!(alice.version, 2);
+    let err = store.put_if_version(owner, "key", &stale).await.expect_err("should conflict");
+    assert!(
+        matches!(
+            err,
+            StateError::Conflict {
+                expected: 1,
+                found: 2,
+                ..
+            }
+        ),
+        "stale write should conflict: {err}"
+    );
+    assert_eq!(store.get::<Body>(owner, "key").await.expect("should get"), alice);
+
+    store.purge(owner, "key").await.expect("should purge");
+}
+
+async fn list(store: &impl StateStore) {
+    let owner = "conformance-list";
+    for key in ["a1", "b1", "b2", "b3", "c1"] {
+        store
+            .put(owner, key, &State::with_ttl(body(key), TimeDelta::minutes(5)))
+            .await
+            .expect("should put");
+    }
+
+    let page = store.list(owner, "b", None, 2).await.expect("should list");
+    let keys = page.keys.iter().map(|info| info.key.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, ["b1", "b2"], "list should return the first page in key order");
+
+    let page = store.list(owner, "b", page.cursor.as_deref(), 2).await.expect("should list");
+    let keys = page.keys.iter().map(|info| info.key.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, ["b3"], "list should resume from the cursor");
+    assert_eq!(page.cursor, None, "the last page should not have a cursor");
+
+    let page = store.list(owner, "", None, usize::MAX).await.expect("should list");
+    assert_eq!(page.keys.len(), 5, "a very large limit should return every key");
+    assert_eq!(page.cursor, None, "a very large limit should return a single page");
+
+    let keys = store.scan(owner, "").await.expect("should scan");
+    assert_eq!(keys.len(), 5, "scan should return every key");
+
+    for info in keys {
+        store.purge(owner, &info.key).await.expect("should purge");
+    }
+}
+
+async fn batch(store: &impl StateStore) {
+    let owner = "conformance-batch";
+    let entries = [
+        ("a", State::with_ttl(body("a"), TimeDelta::minutes(5))),
+        ("b", State::with_ttl(body("b"), TimeDelta::minutes(5))),
+        ("expired", State::with_ttl(body("expired"), TimeDelta::seconds(-1))),
+    ];
+    store.put_many(owner, &entries).await.expect("should put");
+
+    let states =
+        store.get_many::<Body>(owner, &["b", "missing", "expired", "a"]).await.expect("should get");
+    let expected = [Some(entries[1].1.clone()), None, None, Some(entries[0].1.clone())];
+    assert_eq!(states, expected, "get_many should return state in key order");
+
+    store.purge_many(owner, &["a", "b", "expired", "missing"]).await.expect("should purge");
+    assert!(store.scan(owner, "").await.expect("should scan").is_empty(), "should purge all");
+}
+
+async fn concurrency<S: StateStore + Clone + 'static>(store: &S) {
+    let owner = "conformance-concurrency";
+    store
+        .put(owner, "counter", &State::with_ttl(body("counter"), TimeDelta::minutes(5)))
+        .await
+        .expect("should put");
+
+    let writers = (0..WRITERS)
+        .map(|_| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                // retry until the increment is applied without conflict
+                for _ in 0..ATTEMPTS {
+                    let increment = |body: &mut Body| body.count += 1;
+                    match store.update(owner, "counter", increment).await {
+                        Ok(_) => return,
+                        Err(StateError::Conflict { .. }) => tokio::task::yield_now().await,
+                        Err(e) => panic!("update should only fail on conflict: {e}"),
+                    }
+                }
+                panic!("update should succeed within {ATTEMPTS} attempts");
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.await.expect("writer should complete");
+    }
+
+    let counter = store.get::<Body>(owner, "counter").await.expect("should get");
+    assert_eq!(counter.body.count, WRITERS, "concurrent updates should not be lost");
+    assert_eq!(counter.version, WRITERS);
+
+    store.purge(owner, "counter").await.expect("should purge");
+}
@@ -0,0 +1,48 @@
+//! # Watch
+//!
+//! Change notifications for stored state, allowing callers to wait for state
+//! to change rather than polling the store.
+
+use std::future::Future;
+
+use chrono::{DateTime, Utc};
+use futures_core::Stream;
+
+use crate::state::{StateError, StateStore};
+
+/// A change made to the state stored for a watched key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// State was written.
+    Put {
+        /// The version of the state written.
+        version: u64,
+
+        /// Time the written state expires.
+        expires_at: DateTime<Utc>,
+    },
+
+    /// State was removed by a purge or take.
+    Removed,
+
+    /// The watcher fell behind and events were dropped. The current state
+    /// should be read from the store, as it may have changed since the last
+    /// event received.
+    Lagged,
+}
+
+/// The `WatchStore` trait is implemented by stores able to notify callers
+/// when state changes.
+pub trait WatchStore: StateStore {
+    /// Watch a key for changes.
+    ///
+    /// The returned stream yields an event for each change made after the
+    /// watch is established and ends when the store is dropped. Removal of
+    /// state by expiry is not reported. Events may be dropped if the watcher
+    /// falls behind, in which case [`ChangeEvent::Lagged`] is yielded in
+    /// their place, so watchers should read the current state after each
+    /// event rather than rely on seeing every change.
+    fn watch(
+        &self, owner: &str, key: &str,
+    ) -> impl Future<Output = Result<impl Stream<Item = ChangeEvent> + Send + 'static, StateError>> + Send;
+}